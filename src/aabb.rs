@@ -1,4 +1,13 @@
-use crate::{shapes::ShapeKind, Vector};
+use crate::{
+    shapes::{CsgOperation, Shape, ShapeKind},
+    Real, Vector,
+};
+
+// How far the rim of a disk reaches along each axis from its center
+fn disk_extent(normal: Vector, radius: Real) -> Vector {
+    let normal = normal.normalize();
+    (Vector::ONE - normal * normal).max(Vector::ZERO).powf(0.5) * radius
+}
 
 #[derive(Debug, Copy, Clone)]
 
@@ -12,14 +21,73 @@ impl Aabb {
         Self { min, max }
     }
 
-    pub fn from_shape(shape: ShapeKind) -> Self {
-        match shape {
+    // Returns None for shapes that extend infinitely
+    pub fn from_shape(shape: &ShapeKind) -> Option<Self> {
+        let aabb = match *shape {
             ShapeKind::Sphere(center, radius) => Self::new(
                 center - Vector::splat(radius),
                 center + Vector::splat(radius),
             ),
             ShapeKind::Triangle(a, b, c) => Self::new(a.min(b).min(c), a.max(b).max(c)),
-        }
+            ShapeKind::Quad(origin, u, v) => {
+                let opposite = origin + u + v;
+                Self::new(
+                    origin.min(origin + u).min(origin + v).min(opposite),
+                    origin.max(origin + u).max(origin + v).max(opposite),
+                )
+            }
+            ShapeKind::Disk(center, normal, radius) => {
+                let extent = disk_extent(normal, radius);
+                Self::new(center - extent, center + extent)
+            }
+            ShapeKind::Box(min, max) | ShapeKind::Sdf(_, min, max) => Self::new(min, max),
+            ShapeKind::Plane(_, _) => return None,
+            ShapeKind::Cylinder(bottom, top, radius) => {
+                Self::from_caps(bottom, top, radius, radius)
+            }
+            ShapeKind::Cone(bottom, top, bottom_radius, top_radius) => {
+                Self::from_caps(bottom, top, bottom_radius, top_radius)
+            }
+            ShapeKind::Torus(center, axis, radius, tube_radius) => {
+                let extent = disk_extent(axis, radius) + Vector::splat(tube_radius);
+                Self::new(center - extent, center + extent)
+            }
+            ShapeKind::Medium(ref boundary, _) => return Self::from_shape(boundary),
+            ShapeKind::VoxelMedium(ref grid, _) => Self::new(grid.min, grid.max),
+            ShapeKind::Csg(operation, ref a, ref b) => {
+                match (operation, Self::from_shape(a), Self::from_shape(b)) {
+                    (CsgOperation::Union, Some(a), Some(b)) => a.surrounding_box(&b),
+                    (CsgOperation::Intersection, Some(a), Some(b)) => {
+                        Self::new(a.min.max(b.min), a.max.min(b.max))
+                    }
+                    (CsgOperation::Intersection, Some(aabb), None)
+                    | (CsgOperation::Intersection, None, Some(aabb))
+                    | (CsgOperation::Difference, Some(aabb), _) => aabb,
+                    _ => return None,
+                }
+            }
+        };
+
+        Some(aabb)
+    }
+
+    // Both caps are disks and the side is straight between them, so the caps bound everything
+    fn from_caps(bottom: Vector, top: Vector, bottom_radius: Real, top_radius: Real) -> Self {
+        let axis = top - bottom;
+        let bottom_extent = disk_extent(axis, bottom_radius);
+        let top_extent = disk_extent(axis, top_radius);
+        Self::new(
+            (bottom - bottom_extent).min(top - top_extent),
+            (bottom + bottom_extent).max(top + top_extent),
+        )
+    }
+
+    // Around all the shapes that don't extend infinitely, None if there are none
+    pub fn from_shapes(shapes: &[Shape]) -> Option<Self> {
+        shapes
+            .iter()
+            .filter_map(|shape| Self::from_shape(&shape.kind))
+            .reduce(|a, b| a.surrounding_box(&b))
     }
 
     pub fn surrounding_box(&self, other: &Self) -> Self {
@@ -0,0 +1,77 @@
+use image::imageops::flip_vertical_in_place;
+use image::ImageBuffer;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, debug::heat, luminance, Real, Vector};
+
+// Darker pixels than this hide the noise, so they don't need as many samples to look clean
+const MIN_BRIGHTNESS: Real = 0.05;
+
+// After the `samples` of the config, pixels keep getting samples until the noise is below the
+// threshold or they reach the max
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct AdaptiveSampling {
+    pub max_samples: usize,
+    // Standard error of the brightness relative to the brightness itself
+    pub threshold: Real,
+    // Also save an image with the samples taken on each pixel
+    pub sample_map: bool,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        Self {
+            max_samples: 256,
+            threshold: 0.02,
+            sample_map: false,
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn wants_more(&self, samples: usize, variance: &Variance) -> bool {
+        samples < self.max_samples && variance.error() > self.threshold
+    }
+}
+
+// Running variance of the brightness of the samples (Welford's algorithm)
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Variance {
+    count: usize,
+    mean: Real,
+    squares: Real, // Sum of the squared differences to the mean
+}
+
+impl Variance {
+    pub fn add(&mut self, color: Vector) {
+        let value = luminance(color);
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as Real;
+        self.squares += delta * (value - self.mean);
+    }
+
+    // How far off the average of the samples is likely to be, relative to the average
+    pub fn error(&self) -> Real {
+        if self.count < 2 {
+            return Real::INFINITY;
+        }
+        let variance = self.squares / (self.count - 1) as Real;
+        (variance / self.count as Real).sqrt() / self.mean.max(MIN_BRIGHTNESS)
+    }
+}
+
+// Heatmap from blue for the fewest samples to red for the max
+pub fn save_sample_map(samples: &[usize], config: &Config, name: &str) {
+    let max = samples.iter().copied().max().unwrap_or(1).max(1) as Real;
+
+    let mut imgbuf = ImageBuffer::new(config.width as u32, config.height as u32);
+    for (img_pixel, samples) in imgbuf.pixels_mut().zip(samples) {
+        let color = heat(*samples as Real / max) * 255.0;
+        *img_pixel = image::Rgb([color.x as u8, color.y as u8, color.z as u8]);
+    }
+
+    flip_vertical_in_place(&mut imgbuf);
+    imgbuf.save(format!("{name}_samples.png")).unwrap();
+}
@@ -0,0 +1,100 @@
+use image::imageops::flip_vertical_in_place;
+use image::{ImageBuffer, Rgb};
+
+use crate::{config::Config, integrators::Radiance, ray::Ray, world::World, Real, Vector};
+
+// Arbitrary output variables: passes for compositing, saved next to the beauty image. All of
+// them but the IDs are summed over the samples of the pixel, then averaged
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Aovs {
+    pub albedo: Vector,
+    pub normal: Vector, // Shading normal facing the camera
+    pub depth: Real,    // Distance to the camera, 0 where the sky is seen
+    // Index of the shape and material plus one, 0 is the sky. Taken from the first sample
+    // that hits something, averaging them would make up IDs at the edges
+    pub object_id: usize,
+    pub material_id: usize,
+    pub emission: Vector,
+    pub direct: Vector,
+    pub indirect: Vector,
+}
+
+impl Aovs {
+    // Passes of one camera ray, given the light and the first hit the integrator found for it
+    pub fn sample(ray: &Ray, world: &World, radiance: &Radiance) -> Aovs {
+        let mut aovs = Aovs {
+            emission: radiance.emission,
+            direct: radiance.direct,
+            indirect: radiance.indirect,
+            ..Default::default()
+        };
+
+        if let Some(hit) = &radiance.first_hit {
+            let info = hit.get_hit_info(ray);
+            aovs.albedo = world.scene.materials[info.material].albedo();
+            aovs.normal = info.normal;
+            aovs.depth = hit.t;
+            aovs.object_id = hit.shape + 1;
+            aovs.material_id = info.material + 1;
+        }
+
+        aovs
+    }
+
+    pub fn average(self, samples: usize) -> Aovs {
+        let samples = samples.max(1) as Real;
+        Aovs {
+            albedo: self.albedo / samples,
+            normal: self.normal / samples,
+            depth: self.depth / samples,
+            emission: self.emission / samples,
+            direct: self.direct / samples,
+            indirect: self.indirect / samples,
+            ..self
+        }
+    }
+
+    pub fn add(&mut self, sample: &Aovs) {
+        self.albedo += sample.albedo;
+        self.normal += sample.normal;
+        self.depth += sample.depth;
+        self.emission += sample.emission;
+        self.direct += sample.direct;
+        self.indirect += sample.indirect;
+
+        if self.object_id == 0 {
+            self.object_id = sample.object_id;
+            self.material_id = sample.material_id;
+        }
+    }
+}
+
+// Name and value of a pass
+type Pass = (&'static str, fn(&Aovs) -> Vector);
+
+// Every pass goes to its own EXR file, named after the beauty image plus the pass
+pub fn save_aovs(aovs: &[Aovs], config: &Config, name: &str) {
+    let passes: [Pass; 8] = [
+        ("albedo", |aovs| aovs.albedo),
+        ("normal", |aovs| aovs.normal),
+        ("depth", |aovs| Vector::splat(aovs.depth)),
+        ("object_id", |aovs| Vector::splat(aovs.object_id as Real)),
+        ("material_id", |aovs| {
+            Vector::splat(aovs.material_id as Real)
+        }),
+        ("emission", |aovs| aovs.emission),
+        ("direct", |aovs| aovs.direct),
+        ("indirect", |aovs| aovs.indirect),
+    ];
+
+    for (pass, value) in passes {
+        let mut imgbuf = ImageBuffer::new(config.width as u32, config.height as u32);
+        for (img_pixel, aovs) in imgbuf.pixels_mut().zip(aovs) {
+            let color = value(aovs);
+            *img_pixel = Rgb([color.x as f32, color.y as f32, color.z as f32]);
+        }
+
+        flip_vertical_in_place(&mut imgbuf);
+        imgbuf.save(format!("{name}_{pass}.exr")).unwrap();
+    }
+}
@@ -0,0 +1,575 @@
+use crate::{
+    hit::{Hit, HitInfo},
+    integrators::{escaped_weight, light_contribution, sky_lighting, Integrator, Radiance},
+    lights::Light,
+    materials::{Material, MaterialRef},
+    ray::Ray,
+    sampler::Sampler,
+    shapes::ShapeRef,
+    spectrum::Wavelengths,
+    world::World,
+    Real, Vector,
+};
+
+enum Kind {
+    Camera,
+    Light {
+        light: usize,
+        normal: Option<Vector>, // None for the lights at a single point
+    },
+    Surface {
+        shape: ShapeRef,
+        material: MaterialRef,
+        normal: Vector, // Outwards, whichever side the path arrived from
+        uv: (Real, Real),
+    },
+}
+
+// Point where a path from the camera or from a light touches the scene
+struct Vertex {
+    kind: Kind,
+    point: Vector,
+    // Light carried to the vertex for the paths from the lights, how much of the light leaving
+    // the vertex reaches the camera for the paths from the camera
+    throughput: Vector,
+    // Densities over area of reaching the vertex from the start of its path, and from the other
+    // end if the path had been built the other way around
+    pdf_forward: Real,
+    pdf_reverse: Real,
+    delta: bool, // Scatters towards exact directions, nothing can connect to it
+}
+
+impl Vertex {
+    fn camera(point: Vector) -> Self {
+        Self {
+            kind: Kind::Camera,
+            point,
+            throughput: Vector::ONE,
+            pdf_forward: 1.0,
+            pdf_reverse: 0.0,
+            delta: false,
+        }
+    }
+
+    fn material<'a>(&self, world: &'a World) -> Option<&'a Material> {
+        match self.kind {
+            Kind::Surface { material, .. } => Some(&world.scene.materials[material]),
+            _ => None,
+        }
+    }
+
+    // Light the vertex is on, with its normal
+    fn light(&self, world: &World) -> Option<(usize, Option<Vector>)> {
+        match self.kind {
+            Kind::Light { light, normal } => Some((light, normal)),
+            Kind::Surface { shape, normal, .. } => {
+                Some((world.lights.of_shape(shape)?, Some(normal)))
+            }
+            Kind::Camera => None,
+        }
+    }
+
+    fn is_delta_light(&self) -> bool {
+        matches!(self.kind, Kind::Light { normal: None, .. })
+    }
+
+    // How much of the light along the direction the vertex catches. Media and points have no
+    // surface to slant it
+    fn cos(&self, world: &World, direction: Vector) -> Real {
+        match self.kind {
+            Kind::Surface {
+                material, normal, ..
+            } if !world.scene.materials[material].is_medium() => normal.dot(direction).abs(),
+            Kind::Light {
+                normal: Some(normal),
+                ..
+            } => normal.dot(direction).abs(),
+            _ => 1.0,
+        }
+    }
+
+    // From a density over the directions leaving the vertex to one over the area at `next`
+    fn convert_density(&self, world: &World, pdf: Real, next: &Vertex) -> Real {
+        let to_next = next.point - self.point;
+        let distance_squared = to_next.length_squared();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+        pdf * next.cos(world, to_next / distance_squared.sqrt()) / distance_squared
+    }
+
+    // The ray and the hit the materials expect, for light arriving along `from`. The direction
+    // points away from the vertex, like every direction here
+    fn seen_from(&self, from: Vector) -> Option<(Ray, HitInfo)> {
+        let (material, normal, uv) = match self.kind {
+            Kind::Surface {
+                material,
+                normal,
+                uv,
+                ..
+            } => (material, normal, uv),
+            _ => return None,
+        };
+
+        let front_face = normal.dot(from) > 0.0;
+        let info = HitInfo {
+            point: self.point,
+            normal: if front_face { normal } else { -normal },
+            uv,
+            front_face,
+            material,
+        };
+        Some((Ray::new(self.point + from, -from), info))
+    }
+
+    // Fraction of the light arriving along `from` that leaves towards `to`, cosine included
+    fn eval(&self, world: &World, from: Vector, to: Vector) -> Vector {
+        self.seen_from(from)
+            .and_then(|(ray, info)| {
+                world.scene.materials[info.material].eval(&ray, &info, to.normalize())
+            })
+            .unwrap_or(Vector::ZERO)
+    }
+
+    // Density of the path going on from the vertex to `next`, over the area at `next`. Paths
+    // leaving a light have nowhere they come from
+    fn pdf(&self, world: &World, previous: Option<&Vertex>, next: &Vertex) -> Real {
+        let previous = match (&self.kind, previous) {
+            (Kind::Light { .. }, _) | (_, None) => return self.pdf_light(world, next),
+            (_, Some(previous)) => previous,
+        };
+
+        let pdf = match self.seen_from(previous.point - self.point) {
+            Some((ray, info)) => {
+                world.scene.materials[info.material].pdf(&ray, &info, next.point - self.point)
+            }
+            None => 0.0,
+        };
+        self.convert_density(world, pdf, next)
+    }
+
+    // Density of the light at the vertex sending its light to `next`, over the area at `next`
+    fn pdf_light(&self, world: &World, next: &Vertex) -> Real {
+        let (light, normal) = match self.light(world) {
+            Some(light) => light,
+            None => return 0.0,
+        };
+        let direction = (next.point - self.point).normalize();
+        let pdf = world
+            .lights
+            .direction_pdf(&world.scene, light, normal, direction);
+        self.convert_density(world, pdf, next)
+    }
+
+    // Density of a path from the lights starting at the vertex
+    fn pdf_light_origin(&self, world: &World) -> Real {
+        self.light(world).map_or(0.0, |(light, _)| {
+            world.lights.origin_pdf(&world.scene, light)
+        })
+    }
+}
+
+// Follows the ray around the scene adding a vertex on every hit, `pdf` being the density of the
+// direction of the ray. Returns the ray that escaped the scene, if any, and the throughput it
+// carried
+fn random_walk(
+    world: &World,
+    mut ray: Ray,
+    mut throughput: Vector,
+    mut pdf: Real,
+    sampler: &mut dyn Sampler,
+    max_vertices: usize,
+    path: &mut Vec<Vertex>,
+) -> Option<(Ray, Vector)> {
+    while path.len() < max_vertices {
+        let h = match world.sample_hit(&ray, sampler) {
+            Some(h) => h,
+            None => return Some((ray, throughput)),
+        };
+        let info = h.get_hit_info(&ray);
+        let material = &world.scene.materials[info.material];
+
+        let mut vertex = Vertex {
+            kind: Kind::Surface {
+                shape: h.shape,
+                material: info.material,
+                normal: if info.front_face {
+                    info.normal
+                } else {
+                    -info.normal
+                },
+                uv: info.uv,
+            },
+            point: info.point,
+            throughput,
+            pdf_forward: 0.0,
+            pdf_reverse: 0.0,
+            delta: material.is_specular(),
+        };
+        vertex.pdf_forward = path.last()?.convert_density(world, pdf, &vertex);
+        path.push(vertex);
+
+        let (scattered, attenuation) = material.scatter(&ray, &info, sampler)?;
+        // Mirrors and glass have no density, the weights leave them out
+        let last = path.len() - 1;
+        let pdf_reverse = if material.is_specular() {
+            pdf = 0.0;
+            0.0
+        } else {
+            pdf = material.pdf(&ray, &info, scattered.direction);
+            let vertex = &path[last];
+            let (back, back_info) = vertex.seen_from(scattered.direction)?;
+            let pdf_reverse = material.pdf(&back, &back_info, -ray.direction);
+            vertex.convert_density(world, pdf_reverse, &path[last - 1])
+        };
+        path[last - 1].pdf_reverse = pdf_reverse;
+
+        throughput *= attenuation;
+        ray = scattered;
+    }
+    None
+}
+
+// Starts at a point on one of the lights picked by their power
+fn light_path(world: &World, sampler: &mut dyn Sampler, max_vertices: usize) -> Vec<Vertex> {
+    let mut path = Vec::new();
+    let origin = match world.lights.sample_origin(&world.scene, sampler) {
+        Some(origin) if max_vertices > 0 => origin,
+        _ => return path,
+    };
+    let (direction, pdf_direction) =
+        match world
+            .lights
+            .sample_direction(&world.scene, &origin, sampler.get_2d())
+        {
+            Some(sample) => sample,
+            None => return path,
+        };
+    let emitted = world
+        .lights
+        .emitted(&world.scene, origin.light, origin.normal, direction);
+
+    path.push(Vertex {
+        kind: Kind::Light {
+            light: origin.light,
+            normal: origin.normal,
+        },
+        point: origin.point,
+        throughput: emitted / origin.pdf,
+        pdf_forward: origin.pdf,
+        pdf_reverse: 0.0,
+        delta: false,
+    });
+    if pdf_direction == 0.0 || emitted == Vector::ZERO {
+        return path;
+    }
+
+    let cos = origin
+        .normal
+        .map_or(1.0, |normal| normal.dot(direction).abs());
+    let throughput = emitted * cos / (origin.pdf * pdf_direction);
+    let ray = Ray::new(origin.point, direction);
+    random_walk(
+        world,
+        ray,
+        throughput,
+        pdf_direction,
+        sampler,
+        max_vertices,
+        &mut path,
+    );
+    path
+}
+
+// Light through the path made of the first `s` vertices of the light path and the first `t` of
+// the camera path, joined at their ends. With `s` at 1 a new point is picked on the lights
+fn connect(
+    world: &World,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    (s, t): (usize, usize),
+    sampler: &mut dyn Sampler,
+) -> Vector {
+    let camera = &camera_path[t - 1];
+    let from_camera = camera_path[t - 2].point - camera.point;
+
+    let (light, sampled) = match s {
+        // The camera path found a light on its own
+        0 => match camera.material(world) {
+            Some(material) => (camera.throughput * material.emitted(), None),
+            None => return Vector::ZERO,
+        },
+        1 => {
+            if camera.delta {
+                return Vector::ZERO;
+            }
+            let origin = match world.lights.sample_origin(&world.scene, sampler) {
+                Some(origin) => origin,
+                None => return Vector::ZERO,
+            };
+            let to_light = origin.point - camera.point;
+            let distance_squared = to_light.length_squared();
+            let distance = distance_squared.sqrt();
+            let direction = to_light / distance;
+
+            let emitted =
+                world
+                    .lights
+                    .emitted(&world.scene, origin.light, origin.normal, -direction);
+            let cos_light = origin
+                .normal
+                .map_or(1.0, |normal| normal.dot(direction).abs());
+            let light = camera.throughput
+                * camera.eval(world, from_camera, direction)
+                * emitted
+                * cos_light
+                / (distance_squared * origin.pdf);
+            if light == Vector::ZERO {
+                return Vector::ZERO;
+            }
+            let light = light * world.transmittance(camera.point, direction, distance, sampler);
+
+            let sampled = Vertex {
+                kind: Kind::Light {
+                    light: origin.light,
+                    normal: origin.normal,
+                },
+                point: origin.point,
+                throughput: emitted / origin.pdf,
+                pdf_forward: origin.pdf,
+                pdf_reverse: 0.0,
+                delta: false,
+            };
+            (light, Some(sampled))
+        }
+        _ => {
+            let vertex = &light_path[s - 1];
+            if vertex.delta || camera.delta {
+                return Vector::ZERO;
+            }
+            let to_camera = camera.point - vertex.point;
+            let distance_squared = to_camera.length_squared();
+            let distance = distance_squared.sqrt();
+            let direction = to_camera / distance;
+
+            let from_light = light_path[s - 2].point - vertex.point;
+            let light = vertex.throughput
+                * vertex.eval(world, from_light, direction)
+                * camera.eval(world, from_camera, -direction)
+                * camera.throughput
+                / distance_squared;
+            if light == Vector::ZERO {
+                return Vector::ZERO;
+            }
+            let light = light * world.transmittance(camera.point, -direction, distance, sampler);
+            (light, None)
+        }
+    };
+
+    if light == Vector::ZERO {
+        return Vector::ZERO;
+    }
+    light * mis_weight(world, light_path, camera_path, sampled.as_ref(), (s, t))
+}
+
+// Balance heuristic over the ways of building the same path with a different number of vertices
+// from each end (Veach, 1997). The vertices at the join get the densities they would have had
+// with the path built the other ways. Paths from the lights never connect to the camera, those
+// ways are left out
+fn mis_weight(
+    world: &World,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    sampled: Option<&Vertex>,
+    (s, t): (usize, usize),
+) -> Real {
+    // Hitting the light straight from the camera is the only way to find it
+    if s + t == 2 {
+        return 1.0;
+    }
+    let camera = &camera_path[t - 1];
+    let before_camera = &camera_path[t - 2];
+    // Lights the light paths can't start from are only found by the camera paths
+    if s == 0 && camera.light(world).is_none() {
+        return 1.0;
+    }
+
+    let light = match s {
+        0 => None,
+        1 => sampled,
+        _ => Some(&light_path[s - 1]),
+    };
+    let before_light = s.checked_sub(2).map(|i| &light_path[i]);
+    let delta_origin = match s {
+        1 => sampled.is_some_and(Vertex::is_delta_light),
+        _ => light_path.first().is_some_and(Vertex::is_delta_light),
+    };
+
+    // Forward and reverse densities and whether it is delta, for each vertex
+    let densities = |vertex: &Vertex| (vertex.pdf_forward, vertex.pdf_reverse, vertex.delta);
+    let mut cameras = camera_path[..t].iter().map(densities).collect::<Vec<_>>();
+    let mut lights = light_path[..s].iter().map(densities).collect::<Vec<_>>();
+    if let Some(sampled) = sampled {
+        lights[0] = densities(sampled);
+    }
+
+    match light {
+        Some(light) => {
+            cameras[t - 1].1 = light.pdf(world, before_light, camera);
+            cameras[t - 2].1 = camera.pdf(world, Some(light), before_camera);
+            lights[s - 1].1 = camera.pdf(world, Some(before_camera), light);
+            if let Some(before_light) = before_light {
+                lights[s - 2].1 = light.pdf(world, Some(camera), before_light);
+            }
+            lights[s - 1].2 = false;
+        }
+        None => {
+            cameras[t - 1].1 = camera.pdf_light_origin(world);
+            cameras[t - 2].1 = camera.pdf_light(world, before_camera);
+        }
+    }
+    cameras[t - 1].2 = false;
+
+    // Densities of the other ways relative to this one, with the delta ones standing in at 1
+    let remap = |pdf: Real| if pdf == 0.0 { 1.0 } else { pdf };
+    let mut others = 0.0;
+
+    let mut ratio = 1.0;
+    for i in (2..t).rev() {
+        ratio *= remap(cameras[i].1) / remap(cameras[i].0);
+        if !cameras[i].2 && !cameras[i - 1].2 {
+            others += ratio;
+        }
+    }
+
+    let mut ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(lights[i].1) / remap(lights[i].0);
+        let delta_before = if i > 0 { lights[i - 1].2 } else { delta_origin };
+        if !lights[i].2 && !delta_before {
+            others += ratio;
+        }
+    }
+
+    1.0 / (1.0 + others)
+}
+
+// Builds a path from the camera and another one from a light, and joins every pair of their
+// vertices. Finds the light that gets to the scene through small openings or glass, which the
+// paths from the camera alone rarely find
+pub struct Bidirectional {
+    depth: usize,
+}
+
+impl Bidirectional {
+    pub fn new(depth: usize) -> Self {
+        Self { depth }
+    }
+
+    // The paths from the lights can't start from the sky or the directional lights, the camera
+    // path picks them up the way the path tracer does
+    fn distant_lighting(
+        &self,
+        world: &World,
+        camera_path: &[Vertex],
+        escaped: Option<(Ray, Vector)>,
+        sampler: &mut dyn Sampler,
+        radiance: &mut Radiance,
+    ) {
+        for (bounces, pair) in camera_path.windows(2).enumerate() {
+            let (previous, vertex) = (&pair[0], &pair[1]);
+            if vertex.delta || bounces >= self.depth {
+                continue;
+            }
+            let (ray, info) = match vertex.seen_from(previous.point - vertex.point) {
+                Some(seen) => seen,
+                None => continue,
+            };
+            let material = &world.scene.materials[info.material];
+
+            let mut direct = sky_lighting(world, &ray, &info, material, sampler, &Wavelengths::RGB);
+            for light in &world.scene.lights {
+                if let (Light::Directional(..), Some(sample)) = (light, light.sample(info.point)) {
+                    direct += light_contribution(
+                        world,
+                        &ray,
+                        &info,
+                        material,
+                        &sample,
+                        sampler,
+                        &Wavelengths::RGB,
+                    );
+                }
+            }
+            radiance.gather(bounces + 1, vertex.throughput * direct);
+        }
+
+        if let Some((ray, throughput)) = escaped {
+            let scatter_pdf = match camera_path {
+                [.., previous, vertex] if !vertex.delta => vertex
+                    .seen_from(previous.point - vertex.point)
+                    .map(|(last, info)| {
+                        world.scene.materials[info.material].pdf(&last, &info, ray.direction)
+                    }),
+                _ => None,
+            };
+            let weight = escaped_weight(world, ray.direction, scatter_pdf);
+            radiance.gather(
+                camera_path.len() - 1,
+                throughput * world.sky(ray.direction) * weight,
+            );
+        }
+    }
+}
+
+impl Integrator for Bidirectional {
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        let mut radiance = Radiance::default();
+
+        // The density of the camera rays only matters to paths from the lights reaching the
+        // camera, which aren't followed
+        let mut camera_path = vec![Vertex::camera(ray.origin)];
+        let escaped = random_walk(
+            world,
+            *ray,
+            Vector::ONE,
+            1.0,
+            sampler,
+            self.depth + 2,
+            &mut camera_path,
+        );
+        let light_path = light_path(world, sampler, self.depth);
+
+        if let Some(Vertex {
+            kind:
+                Kind::Surface {
+                    shape,
+                    material,
+                    normal,
+                    uv,
+                },
+            point,
+            ..
+        }) = camera_path.get(1)
+        {
+            let hit = Hit::new(ray.origin.distance(*point), *normal, *uv, *material);
+            radiance.first_hit = Some(hit.on_shape(*shape));
+        }
+
+        self.distant_lighting(world, &camera_path, escaped, sampler, &mut radiance);
+
+        for t in 2..=camera_path.len() {
+            // A single vertex from the lights is picked again for every join
+            for s in 0..=light_path.len().max(1) {
+                let bounces = s + t - 2;
+                if bounces > self.depth {
+                    break;
+                }
+                let light = connect(world, &light_path, &camera_path, (s, t), sampler);
+                radiance.gather(bounces, light);
+            }
+        }
+
+        radiance
+    }
+}
@@ -1,64 +1,135 @@
-use nanorand::Rng;
+use crate::{
+    aabb::Aabb,
+    hit::Hit,
+    ray::Ray,
+    shapes::{Shape, ShapeRef},
+    Vector,
+};
 
-use crate::{aabb::Aabb, hit::Hit, ray::Ray, shapes::Shape, Vector};
-
-type ShapeRef = usize;
+pub struct Bvh {
+    root: Option<Box<BvhNode>>,
+    // Shapes that no Aabb can hold, like planes. They are tested against every ray
+    unbounded: Vec<ShapeRef>,
+}
 
 // It's basically a binary tree
 pub enum BVHKind {
-    Node(Box<Bvh>, Box<Bvh>),
+    Node(Box<BvhNode>, Box<BvhNode>),
     Leaf(ShapeRef),
 }
 
-pub struct Bvh {
+pub struct BvhNode {
     pub aabb: Aabb,
     pub kind: BVHKind,
 }
 
+fn closest(a: Option<Hit>, b: Option<Hit>) -> Option<Hit> {
+    match (a, b) {
+        (None, None) => None,
+        (None, Some(h)) => Some(h),
+        (Some(h), None) => Some(h),
+        (Some(h1), Some(h2)) => {
+            if h1.t < h2.t {
+                Some(h1)
+            } else {
+                Some(h2)
+            }
+        }
+    }
+}
+
 impl Bvh {
     pub fn new(shapes: &[Shape]) -> Bvh {
-        let mut aabbs: Vec<(ShapeRef, Aabb)> = shapes
-            .iter()
-            .map(|shape| Aabb::from_shape(shape.kind))
-            .enumerate()
-            .collect();
+        let mut aabbs: Vec<(ShapeRef, Aabb)> = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (shape_ref, shape) in shapes.iter().enumerate() {
+            // Media have no surface to hit, the integrators sample them along the rays instead
+            if shape.kind.is_medium() {
+                continue;
+            }
+            match Aabb::from_shape(&shape.kind) {
+                Some(aabb) => aabbs.push((shape_ref, aabb)),
+                None => unbounded.push(shape_ref),
+            }
+        }
 
-        *Self::create_bvh(&mut aabbs)
+        let root = (!aabbs.is_empty()).then(|| BvhNode::create(&mut aabbs));
+
+        Bvh { root, unbounded }
     }
 
     pub fn hit(&self, ray: &Ray, ray_dir_recip: Vector, shapes: &[Shape]) -> Option<Hit> {
+        self.hit_with_cost(ray, ray_dir_recip, shapes).0
+    }
+
+    // Also returns how many boxes the ray was tested against, to find the expensive parts of a scene
+    pub fn hit_with_cost(
+        &self,
+        ray: &Ray,
+        ray_dir_recip: Vector,
+        shapes: &[Shape],
+    ) -> (Option<Hit>, usize) {
+        let mut tests = 0;
+        let bounded = self
+            .root
+            .as_ref()
+            .and_then(|root| root.hit(ray, ray_dir_recip, shapes, &mut tests));
+
+        let hit = self
+            .unbounded
+            .iter()
+            .map(|shape_ref| {
+                shapes[*shape_ref]
+                    .hit(ray)
+                    .map(|hit| hit.on_shape(*shape_ref))
+            })
+            .fold(bounded, closest);
+
+        (hit, tests)
+    }
+}
+
+impl BvhNode {
+    fn hit(
+        &self,
+        ray: &Ray,
+        ray_dir_recip: Vector,
+        shapes: &[Shape],
+        tests: &mut usize,
+    ) -> Option<Hit> {
+        *tests += 1;
         if !self.aabb.hit(ray.origin, ray_dir_recip) {
             return None;
         }
 
         match &self.kind {
-            BVHKind::Node(left, right) => match (
-                left.hit(ray, ray_dir_recip, shapes),
-                right.hit(ray, ray_dir_recip, shapes),
-            ) {
-                (None, None) => None,
-                (None, Some(h)) => Some(h),
-                (Some(h), None) => Some(h),
-                (Some(h1), Some(h2)) => {
-                    if h1.t < h2.t {
-                        Some(h1)
-                    } else {
-                        Some(h2)
-                    }
-                }
-            },
-            BVHKind::Leaf(shape_ref) => shapes[*shape_ref].hit(ray),
+            BVHKind::Node(left, right) => closest(
+                left.hit(ray, ray_dir_recip, shapes, tests),
+                right.hit(ray, ray_dir_recip, shapes, tests),
+            ),
+            BVHKind::Leaf(shape_ref) => shapes[*shape_ref]
+                .hit(ray)
+                .map(|hit| hit.on_shape(*shape_ref)),
         }
     }
 
-    fn create_bvh(aabbs: &mut [(ShapeRef, Aabb)]) -> Box<Bvh> {
+    fn create(aabbs: &mut [(ShapeRef, Aabb)]) -> Box<BvhNode> {
         let aabb = aabbs
             .iter_mut()
             .map(|(_, aabb)| *aabb)
             .reduce(|a, b| a.surrounding_box(&b))
             .unwrap();
 
-        let axis = nanorand::tls_rng().generate_range(0..3);
+        // Split along the longest side, so the same scene always gets the same tree
+        let size = aabb.max - aabb.min;
+        let axis = if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        };
 
         let kind = if aabbs.len() == 1 {
             BVHKind::Leaf(aabbs.first().unwrap().0)
@@ -66,11 +137,11 @@ impl Bvh {
             aabbs.sort_unstable_by(|(_, a), (_, b)| a.min[axis].partial_cmp(&b.min[axis]).unwrap());
 
             let half = aabbs.len() / 2;
-            let left = Self::create_bvh(&mut aabbs[..half]);
-            let right = Self::create_bvh(&mut aabbs[half..]);
+            let left = Self::create(&mut aabbs[..half]);
+            let right = Self::create(&mut aabbs[half..]);
             BVHKind::Node(left, right)
         };
 
-        Box::new(Bvh { aabb, kind })
+        Box::new(BvhNode { aabb, kind })
     }
 }
@@ -1,11 +1,17 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{Real, Vector};
+use crate::{
+    adaptive::AdaptiveSampling, denoise::Denoiser, environment::Environment, film::Filter,
+    integrators::IntegratorKind, lights::LightSampling, progressive::Progressive,
+    sampler::SamplerKind, Real, Vector,
+};
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub scene: String,
     pub ambient_color: Vector,
+    pub environment: Environment,
     pub width: usize,
     pub height: usize,
     pub aspect_ratio: Real,
@@ -13,6 +19,27 @@ pub struct Config {
     pub ttl: usize,
     pub chunk_size: usize,
     pub bvh_enabled: bool,
+    pub integrator: IntegratorKind,
+    // How the lights are picked for the rays sent towards them
+    pub light_sampling: LightSampling,
+    // Also save albedo, normal, depth, IDs and lighting passes as EXR files
+    pub aovs: bool,
+    // Filter out the noise guided by the albedo, normal and depth of the pixels
+    pub denoiser: Option<Denoiser>,
+    // Keep sampling the noisy pixels past `samples`
+    pub adaptive: Option<AdaptiveSampling>,
+    // Sample the whole image over and over until a time, sample or noise limit instead
+    pub progressive: Option<Progressive>,
+    pub sampler: SamplerKind,
+    // Spreads each sample over the pixels around it
+    pub filter: Filter,
+    // Carry a few wavelengths along each path instead of red, green and blue, so glass with an
+    // index of refraction that changes with the wavelength splits the light. Path tracing only
+    pub spectral: bool,
+    // Renders with the same seed come out the same, down to the bit, no matter the threads or
+    // chunks. Progressive renders stopped by the time budget are the exception. A new one is
+    // picked for every render when missing
+    pub seed: Option<u64>,
 }
 
 impl Default for Config {
@@ -28,6 +55,7 @@ impl Default for Config {
         Self {
             ambient_color,
             scene: "scene_one".to_string(),
+            environment: Environment::default(),
             width: WIDHT,
             height: HEIGHT,
             samples: SAMPLES,
@@ -35,6 +63,16 @@ impl Default for Config {
             ttl: TTL,
             aspect_ratio: RATIO,
             bvh_enabled: true,
+            integrator: IntegratorKind::default(),
+            light_sampling: LightSampling::default(),
+            aovs: false,
+            denoiser: None,
+            adaptive: None,
+            progressive: None,
+            sampler: SamplerKind::default(),
+            filter: Filter::default(),
+            spectral: false,
+            seed: None,
         }
     }
 }
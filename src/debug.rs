@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    integrators::{Integrator, Radiance},
+    ray::Ray,
+    sampler::Sampler,
+    world::World,
+    Real, Vector,
+};
+
+// Boxes tested per ray that show up halfway through the heatmap
+const COST_SCALE: Real = 50.0;
+
+// False color renders of what the first ray hits, to find problems in scenes and meshes
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum DebugView {
+    Normals,     // Outward normal, mapped from [-1, 1] to [0, 1]
+    Depth,       // Bright up close, fading with the distance to the camera
+    Material,    // A different color for each material
+    Barycentric, // Barycentric coordinates in triangles, the uv of other shapes
+    FrontFace,   // Green where the ray hits the outside of the surface and red on the inside
+    BvhCost,     // Heatmap from blue to red of the boxes tested to find the hit
+}
+
+fn lerp(a: Vector, b: Vector, t: Real) -> Vector {
+    a + (b - a) * t
+}
+
+// Blue for 0, green for 0.5 and red for 1
+pub fn heat(t: Real) -> Vector {
+    if t < 0.5 {
+        lerp(Vector::Z, Vector::Y, t * 2.0)
+    } else {
+        lerp(Vector::Y, Vector::X, t * 2.0 - 1.0)
+    }
+}
+
+// Spreads the hues of consecutive indices using the golden ratio
+fn index_color(index: usize) -> Vector {
+    let hue = (index as Real * 0.618_033_988_75).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as usize {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    Vector::new(r, g, b) * 0.8 + Vector::splat(0.1)
+}
+
+impl Integrator for DebugView {
+    fn radiance(&self, ray: &Ray, world: &World, _: &mut dyn Sampler) -> Radiance {
+        let (first_hit, cost) = world.hit_with_cost(ray);
+        let hit = first_hit.map(|hit| (hit.t, hit.get_hit_info(ray)));
+
+        let color = match (self, hit) {
+            (DebugView::BvhCost, _) => heat(1.0 - (-(cost as Real) / COST_SCALE).exp()),
+            (_, None) => Vector::ZERO,
+            (DebugView::Normals, Some((_, info))) => {
+                let outward = if info.front_face {
+                    info.normal
+                } else {
+                    -info.normal
+                };
+                (outward.normalize() + Vector::ONE) * 0.5
+            }
+            (DebugView::Depth, Some((t, _))) => {
+                let focus = (world.scene.look_from - world.scene.look_at).length();
+                Vector::splat((-t / focus).exp())
+            }
+            (DebugView::Material, Some((_, info))) => index_color(info.material),
+            (DebugView::Barycentric, Some((_, info))) => {
+                let (u, v) = info.uv;
+                Vector::new(1.0 - u - v, u, v).clamp(Vector::ZERO, Vector::ONE)
+            }
+            (DebugView::FrontFace, Some((_, info))) => {
+                if info.front_face {
+                    Vector::Y
+                } else {
+                    Vector::X
+                }
+            }
+        };
+
+        Radiance {
+            first_hit,
+            ..color.into()
+        }
+    }
+}
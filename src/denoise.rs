@@ -0,0 +1,157 @@
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{aov::Aovs, luminance, Real, Vector};
+
+// B3 spline, applied along rows and columns
+const KERNEL: [Real; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// By then the kernel spreads wider than any image
+const MAX_ITERATIONS: usize = 16;
+
+// Edge avoiding à-trous wavelet filter. Every iteration blurs with the same 5x5 kernel, spread
+// twice as far as the one before, and neighbours only count as much as their albedo, normal and
+// depth look like the ones of the pixel, so the blur stops at the edges of the objects
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct Denoiser {
+    #[serde(deserialize_with = "capped_iterations")]
+    pub iterations: usize,
+    // How different the neighbours can be before they stop counting. Color halves every iteration,
+    // so the first passes clean the noise and the rest keep the details
+    #[serde(deserialize_with = "positive")]
+    pub color_sigma: Real,
+    #[serde(deserialize_with = "positive")]
+    pub albedo_sigma: Real,
+    #[serde(deserialize_with = "positive")]
+    pub normal_sigma: Real,
+    #[serde(deserialize_with = "positive")]
+    pub depth_sigma: Real, // Relative to the depth of the pixel
+}
+
+fn capped_iterations<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    Ok(usize::deserialize(deserializer)?.min(MAX_ITERATIONS))
+}
+
+// A sigma of 0 would make every weight NaN
+fn positive<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Real, D::Error> {
+    let sigma = Real::deserialize(deserializer)?;
+    if sigma > 0.0 {
+        Ok(sigma)
+    } else {
+        Err(de::Error::custom(format!(
+            "The sigmas of the denoiser have to be greater than 0, not {sigma}"
+        )))
+    }
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            color_sigma: 1.0,
+            albedo_sigma: 0.1,
+            normal_sigma: 0.3,
+            depth_sigma: 0.1,
+        }
+    }
+}
+
+fn gaussian(distance_squared: Real, sigma: Real) -> Real {
+    (-distance_squared / (sigma * sigma)).exp()
+}
+
+impl Denoiser {
+    // Filters the light of the pixels, using the albedo, normal and depth of the passes as guides
+    pub fn denoise(&self, pixels: &mut [Vector], guides: &[Aovs], width: usize, height: usize) {
+        let mut colors = pixels.to_vec();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let color_sigma = self.color_sigma / step as Real;
+            let mut filtered = vec![Vector::ZERO; colors.len()];
+
+            filtered
+                .par_chunks_mut(width)
+                .enumerate()
+                .for_each(|(y, row)| {
+                    for (x, out) in row.iter_mut().enumerate() {
+                        let center = y * width + x;
+                        let mut sum = Vector::ZERO;
+                        let mut total = 0.0;
+
+                        for (j, row_weight) in KERNEL.iter().enumerate() {
+                            let qy = y as isize + (j as isize - 2) * step;
+                            if qy < 0 || qy >= height as isize {
+                                continue;
+                            }
+                            for (i, column_weight) in KERNEL.iter().enumerate() {
+                                let qx = x as isize + (i as isize - 2) * step;
+                                if qx < 0 || qx >= width as isize {
+                                    continue;
+                                }
+
+                                let neighbour = qy as usize * width + qx as usize;
+                                let weight = row_weight
+                                    * column_weight
+                                    * self.similarity(
+                                        &guides[center],
+                                        &guides[neighbour],
+                                        colors[center],
+                                        colors[neighbour],
+                                        color_sigma,
+                                    );
+                                sum += colors[neighbour] * weight;
+                                total += weight;
+                            }
+                        }
+
+                        // The pixel itself always counts, so the total is never 0
+                        *out = sum / total;
+                    }
+                });
+
+            colors = filtered;
+        }
+
+        pixels.copy_from_slice(&colors);
+    }
+
+    fn similarity(
+        &self,
+        a: &Aovs,
+        b: &Aovs,
+        color_a: Vector,
+        color_b: Vector,
+        color_sigma: Real,
+    ) -> Real {
+        let depth = (a.depth - b.depth).abs() / (self.depth_sigma * a.depth.max(1e-3));
+
+        // Compare the brightness squashed into [0, 1), otherwise fireflies never blend in
+        let squash = |color: Vector| {
+            let luminance = luminance(color);
+            luminance / (1.0 + luminance)
+        };
+        let color = squash(color_a) - squash(color_b);
+
+        gaussian(color * color, color_sigma)
+            * gaussian(a.albedo.distance_squared(b.albedo), self.albedo_sigma)
+            * gaussian(a.normal.distance_squared(b.normal), self.normal_sigma)
+            * (-depth).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_checked() {
+        let denoiser = serde_json::from_str::<Denoiser>(r#"{"iterations": 40}"#).unwrap();
+        assert_eq!(denoiser.iterations, MAX_ITERATIONS);
+        assert!(serde_json::from_str::<Denoiser>(r#"{"color_sigma": 0.0}"#).is_err());
+        assert!(serde_json::from_str::<Denoiser>(r#"{"depth_sigma": -1.0}"#).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::Real;
+
+// Piecewise constant distribution over [0, 1), proportional to the values of its pieces
+pub struct Distribution1D {
+    values: Vec<Real>,
+    cdf: Vec<Real>,
+    integral: Real,
+}
+
+impl Distribution1D {
+    pub fn new(values: Vec<Real>) -> Self {
+        let count = values.len() as Real;
+        let mut cdf = vec![0.0; values.len() + 1];
+        for (i, value) in values.iter().enumerate() {
+            cdf[i + 1] = cdf[i] + value.abs() / count;
+        }
+        let integral = cdf[values.len()];
+
+        // Nothing to go by, every piece is as likely
+        if integral == 0.0 {
+            for (i, step) in cdf.iter_mut().enumerate() {
+                *step = i as Real / count;
+            }
+        } else {
+            cdf.iter_mut().for_each(|step| *step /= integral);
+        }
+
+        Self {
+            values,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn integral(&self) -> Real {
+        self.integral
+    }
+
+    // Point in [0, 1) for the random number, its density and the piece it is in
+    pub fn sample(&self, u: Real) -> (Real, Real, usize) {
+        // Last step of the cdf that is not past u
+        let piece = self
+            .cdf
+            .partition_point(|step| *step <= u)
+            .clamp(1, self.values.len())
+            - 1;
+
+        let width = self.cdf[piece + 1] - self.cdf[piece];
+        let offset = if width > 0.0 {
+            (u - self.cdf[piece]) / width
+        } else {
+            0.0
+        };
+
+        let point = (piece as Real + offset) / self.values.len() as Real;
+        (point.min(1.0 - Real::EPSILON), self.pdf(piece), piece)
+    }
+
+    pub fn pdf(&self, piece: usize) -> Real {
+        if self.integral == 0.0 {
+            return 1.0;
+        }
+        self.values[piece].abs() / self.integral
+    }
+}
+
+// Distribution over [0, 1)², picking a row first and then a point along it
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // Values of the pieces, row by row
+    pub fn new(values: &[Real], width: usize) -> Self {
+        let rows = values
+            .chunks(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect::<Vec<_>>();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral()).collect());
+
+        Self { rows, marginal }
+    }
+
+    // Point as (along the row, across rows) and its density
+    pub fn sample(&self, (u, v): (Real, Real)) -> ((Real, Real), Real) {
+        let (y, row_pdf, row) = self.marginal.sample(v);
+        let (x, pdf, _) = self.rows[row].sample(u);
+        ((x, y), row_pdf * pdf)
+    }
+
+    pub fn pdf(&self, (x, y): (Real, Real)) -> Real {
+        let row = ((y * self.rows.len() as Real) as usize).min(self.rows.len() - 1);
+        let columns = self.rows[row].values.len();
+        let column = ((x * columns as Real) as usize).min(columns - 1);
+        self.marginal.pdf(row) * self.rows[row].pdf(column)
+    }
+}
+
+// Picks one of the values with a probability proportional to it in constant time, from a single
+// random number (Vose's alias method)
+pub struct AliasTable {
+    // Probability of keeping each bin, and the one it hands over to otherwise
+    bins: Vec<(Real, usize)>,
+    pmf: Vec<Real>,
+}
+
+impl AliasTable {
+    pub fn new(values: &[Real]) -> Self {
+        let count = values.len();
+        let total = values.iter().map(|value| value.abs()).sum::<Real>();
+        let pmf = values
+            .iter()
+            .map(|value| {
+                if total > 0.0 {
+                    value.abs() / total
+                } else {
+                    1.0 / count as Real
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Bins under the average get topped up by one over it
+        let mut scaled = pmf.iter().map(|p| p * count as Real).collect::<Vec<_>>();
+        let (mut under, mut over): (Vec<_>, Vec<_>) = (0..count).partition(|i| scaled[*i] < 1.0);
+        let mut bins = vec![(1.0, 0); count];
+        while let (Some(small), Some(&large)) = (under.pop(), over.last()) {
+            bins[small] = (scaled[small], large);
+            scaled[large] -= 1.0 - scaled[small];
+            if scaled[large] < 1.0 {
+                over.pop();
+                under.push(large);
+            }
+        }
+        // Whatever is left is full up to rounding
+        for i in under.into_iter().chain(over) {
+            bins[i] = (1.0, i);
+        }
+
+        Self { bins, pmf }
+    }
+
+    // Index and its probability
+    pub fn sample(&self, u: Real) -> (usize, Real) {
+        let scaled = u * self.bins.len() as Real;
+        let bin = (scaled as usize).min(self.bins.len() - 1);
+        let (keep, alias) = self.bins[bin];
+        let index = if scaled - (bin as Real) < keep {
+            bin
+        } else {
+            alias
+        };
+        (index, self.pmf[index])
+    }
+
+    pub fn pmf(&self, index: usize) -> Real {
+        self.pmf[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_follow_the_values() {
+        let distribution = Distribution1D::new(vec![0.0, 3.0, 1.0, 0.0]);
+
+        let (point, pdf, piece) = distribution.sample(0.5);
+        assert_eq!(piece, 1);
+        assert!((point - (1.0 + 0.5 / 0.75) / 4.0).abs() < 1e-9);
+        assert_eq!(pdf, 3.0);
+
+        let (_, pdf, piece) = distribution.sample(0.9);
+        assert_eq!((piece, pdf), (2, 1.0));
+    }
+
+    #[test]
+    fn pdf_matches_sample_2d() {
+        let distribution = Distribution2D::new(&[1.0, 0.0, 2.0, 5.0, 0.0, 4.0], 3);
+        for uv in [(0.1, 0.2), (0.7, 0.9), (0.4, 0.5)] {
+            let (point, pdf) = distribution.sample(uv);
+            assert!((distribution.pdf(point) - pdf).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn alias_table_picks_in_proportion() {
+        let table = AliasTable::new(&[1.0, 0.0, 3.0, 4.0]);
+        let mut counts = [0; 4];
+        for i in 0..8000 {
+            let (index, pmf) = table.sample((i as Real + 0.5) / 8000.0);
+            assert_eq!(pmf, table.pmf(index));
+            counts[index] += 1;
+        }
+        assert_eq!(counts, [1000, 0, 3000, 4000]);
+    }
+}
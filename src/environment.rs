@@ -0,0 +1,241 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use image::{codecs::hdr::HdrDecoder, ImageError, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::{distribution::Distribution2D, luminance, sampler::Sampler, sky::Sky, Real, Vector};
+
+// Light coming from the sky for rays that escape the scene
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum Environment {
+    // From white below to `ambient_color` straight up
+    #[default]
+    Gradient,
+    Map(EnvironmentMap),
+    Sky(Box<Sky>),
+}
+
+pub struct SkySample {
+    pub direction: Vector,
+    pub radiance: Vector,
+    pub pdf: Real, // Over solid angle
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vector, ambient_color: Vector) -> Vector {
+        match self {
+            Environment::Gradient => {
+                let t = 0.5 * (direction.y + 1.0);
+                Vector::splat(1.0) * (1.0 - t) + ambient_color * t
+            }
+            Environment::Map(map) => map.radiance(direction),
+            Environment::Sky(sky) => sky.radiance(direction),
+        }
+    }
+
+    // Direction towards the bright parts of the sky. None for the environments that are too
+    // smooth to bother, without using up a dimension of the sampler
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<SkySample> {
+        match self {
+            Environment::Gradient => None,
+            Environment::Map(map) => map.sample(sampler.get_2d()),
+            Environment::Sky(sky) => sky.sample(sampler.get_2d()),
+        }
+    }
+
+    // Density of `sample` picking the direction, for the environments that can be sampled
+    pub fn pdf(&self, direction: Vector) -> Option<Real> {
+        match self {
+            Environment::Gradient => None,
+            Environment::Map(map) => Some(map.pdf(direction)),
+            Environment::Sky(sky) => Some(sky.pdf(direction)),
+        }
+    }
+}
+
+// How the map is written in the config
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EnvironmentFile {
+    path: String,
+    #[serde(default)]
+    rotation: Real, // Degrees around Y
+    #[serde(default = "full_intensity")]
+    intensity: Real,
+}
+
+fn full_intensity() -> Real {
+    1.0
+}
+
+// Equirectangular image around the scene, HDR or EXR. The top row is straight up, the bottom one
+// straight down, and the center looks towards -Z
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "EnvironmentFile", into = "EnvironmentFile")]
+pub struct EnvironmentMap {
+    file: EnvironmentFile,
+    width: usize,
+    height: usize,
+    texels: Arc<[Vector]>,
+    // Proportional to the brightness of the texels and the solid angle they cover
+    distribution: Arc<Distribution2D>,
+}
+
+impl TryFrom<EnvironmentFile> for EnvironmentMap {
+    type Error = String;
+
+    fn try_from(file: EnvironmentFile) -> Result<Self, Self::Error> {
+        let error = |e: ImageError| format!("Could not read {}: {e}", file.path);
+        let to_texel =
+            |pixel: &Rgb<f32>| Vector::new(pixel[0] as Real, pixel[1] as Real, pixel[2] as Real);
+
+        // The generic loader tone maps HDR files down to 8 bits, their decoder keeps the floats
+        let (width, height, texels) = if file.path.to_lowercase().ends_with(".hdr") {
+            let reader = BufReader::new(File::open(&file.path).map_err(|e| error(e.into()))?);
+            let decoder = HdrDecoder::new(reader).map_err(error)?;
+            let metadata = decoder.metadata();
+            let pixels = decoder.read_image_hdr().map_err(error)?;
+            (
+                metadata.width,
+                metadata.height,
+                pixels.iter().map(to_texel).collect(),
+            )
+        } else {
+            let image = image::open(&file.path).map_err(error)?.into_rgb32f();
+            (
+                image.width(),
+                image.height(),
+                image.pixels().map(to_texel).collect(),
+            )
+        };
+
+        Ok(Self::new(file, width as usize, height as usize, texels))
+    }
+}
+
+impl From<EnvironmentMap> for EnvironmentFile {
+    fn from(map: EnvironmentMap) -> Self {
+        map.file
+    }
+}
+
+impl EnvironmentMap {
+    fn new(file: EnvironmentFile, width: usize, height: usize, texels: Vec<Vector>) -> Self {
+        let texels = texels
+            .into_iter()
+            .map(|texel| texel * file.intensity)
+            .collect::<Vec<_>>();
+
+        // Rows near the poles are squeezed into less solid angle
+        let weights = texels
+            .chunks(width)
+            .enumerate()
+            .flat_map(|(row, texels)| {
+                let sin_theta = ((row as Real + 0.5) / height as Real * PI).sin();
+                texels
+                    .iter()
+                    .map(move |texel| luminance(*texel) * sin_theta)
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            file,
+            width,
+            height,
+            texels: texels.into(),
+            distribution: Arc::new(Distribution2D::new(&weights, width)),
+        }
+    }
+
+    fn rotation(&self) -> Real {
+        self.file.rotation / 360.0
+    }
+
+    // Position on the image, both from 0 to 1
+    fn to_uv(&self, direction: Vector) -> (Real, Real) {
+        let direction = direction.normalize();
+        let phi = direction.x.atan2(-direction.z);
+        let u = ((phi + PI) / (2.0 * PI) - self.rotation()).rem_euclid(1.0);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn to_direction(&self, (u, v): (Real, Real)) -> Vector {
+        let phi = (u + self.rotation()) * 2.0 * PI - PI;
+        let theta = v * PI;
+        Vector::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+
+    fn radiance(&self, direction: Vector) -> Vector {
+        let (u, v) = self.to_uv(direction);
+        let column = ((u * self.width as Real) as usize).min(self.width - 1);
+        let row = ((v * self.height as Real) as usize).min(self.height - 1);
+        self.texels[row * self.width + column]
+    }
+
+    fn sample(&self, uv: (Real, Real)) -> Option<SkySample> {
+        let (point, pdf) = self.distribution.sample(uv);
+        let sin_theta = (point.1 * PI).sin();
+        if pdf == 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+
+        let direction = self.to_direction(point);
+        Some(SkySample {
+            direction,
+            radiance: self.radiance(direction),
+            // The image covers 2π by π radians, squeezed by sin θ away from the equator
+            pdf: pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf(&self, direction: Vector) -> Real {
+        let (u, v) = self.to_uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf((u, v)) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(rotation: Real) -> EnvironmentMap {
+        // Dark everywhere except for one bright texel
+        let (width, height) = (8, 4);
+        let mut texels = vec![Vector::splat(0.01); width * height];
+        texels[width + 5] = Vector::splat(100.0);
+        let file = EnvironmentFile {
+            path: String::new(),
+            rotation,
+            intensity: 2.0,
+        };
+        EnvironmentMap::new(file, width, height, texels)
+    }
+
+    #[test]
+    fn directions_round_trip() {
+        let map = map(30.0);
+        for uv in [(0.1, 0.3), (0.6, 0.5), (0.95, 0.8)] {
+            let (u, v) = map.to_uv(map.to_direction(uv));
+            assert!((u - uv.0).abs() < 1e-9 && (v - uv.1).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn samples_find_the_bright_texel() {
+        let map = map(90.0);
+        let sample = map.sample((0.5, 0.5)).unwrap();
+        assert_eq!(sample.radiance, Vector::splat(200.0));
+        assert!((map.pdf(sample.direction) - sample.pdf).abs() < 1e-6 * sample.pdf);
+    }
+}
@@ -0,0 +1,210 @@
+use std::f64::consts::PI;
+
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Real, Vector};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub enum FilterKind {
+    #[default]
+    Box,
+    Tent,
+    Gaussian, // Standard deviation of a third of the radius
+    Mitchell, // Mitchell-Netravali with B = C = 1/3
+    Lanczos,  // Sinc windowed by a sinc as wide as the radius
+}
+
+// How much a sample counts for the pixels around it, depending on how far it is from their
+// centers. The radius is in pixels, a box of radius 0.5 only covers the pixel of the sample
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: Real,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            kind: FilterKind::Box,
+            radius: 0.5,
+        }
+    }
+}
+
+fn sinc(x: Real) -> Real {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+fn mitchell(x: Real) -> Real {
+    const B: Real = 1.0 / 3.0;
+    const C: Real = 1.0 / 3.0;
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * B - 6.0 * C) * x * x * x
+            + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+            + (6.0 - 2.0 * B))
+            / 6.0
+    } else if x < 2.0 {
+        ((-B - 6.0 * C) * x * x * x
+            + (6.0 * B + 30.0 * C) * x * x
+            + (-12.0 * B - 48.0 * C) * x
+            + (8.0 * B + 24.0 * C))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    fn eval_1d(&self, x: Real) -> Real {
+        // Half open, so a box of radius 0.5 takes samples on the edge between two pixels once
+        let radius = self.radius;
+        if x < -radius || x >= radius {
+            return 0.0;
+        }
+
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => radius - x.abs(),
+            FilterKind::Gaussian => {
+                // Shifted down so it reaches 0 at the radius instead of being cut off
+                let sigma = radius / 3.0;
+                let gaussian = |x: Real| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(radius)
+            }
+            // Defined over [-2, 2], stretched to the radius
+            FilterKind::Mitchell => mitchell(2.0 * x / radius),
+            FilterKind::Lanczos => sinc(x) * sinc(x / radius),
+        }
+    }
+
+    // Separable, the same filter along X and Y
+    pub fn eval(&self, x: Real, y: Real) -> Real {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+
+    // How many pixels away from its own a sample can reach
+    fn reach(&self) -> isize {
+        (self.radius + 0.5).ceil() as isize - 1
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FilmSample {
+    pub x: Real, // Position on the image, in pixels
+    pub y: Real,
+    pub color: Vector,
+}
+
+// Weighted sum of the samples around each pixel, and the sum of the weights
+pub struct Film {
+    width: usize,
+    height: usize,
+    filter: Filter,
+    sums: Vec<(Vector, Real)>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        Self {
+            width,
+            height,
+            filter,
+            sums: vec![(Vector::ZERO, 0.0); width * height],
+        }
+    }
+
+    // Adds a pass with at most one sample per pixel, ordered like the pixels. Splatting each
+    // sample onto its neighbours would have threads racing on the rows at the edges of their
+    // chunks, and adding the floats in whatever order they get there. Instead every row gathers
+    // the samples around its pixels, always in the same order
+    pub fn add_pass(&mut self, samples: &[Option<FilmSample>]) {
+        let (width, height) = (self.width as isize, self.height as isize);
+        let filter = self.filter;
+        let reach = filter.reach();
+
+        self.sums
+            .par_chunks_mut(self.width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, (sum, weights)) in row.iter_mut().enumerate() {
+                    let center_x = x as Real + 0.5;
+                    let center_y = y as Real + 0.5;
+
+                    for sample_y in
+                        (y as isize - reach).max(0)..=(y as isize + reach).min(height - 1)
+                    {
+                        for sample_x in
+                            (x as isize - reach).max(0)..=(x as isize + reach).min(width - 1)
+                        {
+                            let sample = match samples[(sample_y * width + sample_x) as usize] {
+                                Some(sample) => sample,
+                                None => continue,
+                            };
+                            let weight = filter.eval(sample.x - center_x, sample.y - center_y);
+                            *sum += sample.color * weight;
+                            *weights += weight;
+                        }
+                    }
+                }
+            });
+    }
+
+    // Negative lobes can leave pixels without weight, those stay black
+    pub fn pixels(&self) -> Vec<Vector> {
+        self.sums
+            .iter()
+            .map(|(sum, weights)| {
+                if *weights > 0.0 {
+                    *sum / *weights
+                } else {
+                    Vector::ZERO
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_vanish_at_the_radius() {
+        for kind in [
+            FilterKind::Tent,
+            FilterKind::Gaussian,
+            FilterKind::Mitchell,
+            FilterKind::Lanczos,
+        ] {
+            let filter = Filter { kind, radius: 2.0 };
+            assert!(filter.eval(0.0, 0.0) > 0.0, "{kind:?}");
+            assert!(filter.eval(1.999, 0.0).abs() < 1e-2, "{kind:?}");
+            assert_eq!(filter.eval(2.0, 0.0), 0.0, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn box_keeps_samples_in_their_pixel() {
+        let mut film = Film::new(2, 1, Filter::default());
+        let sample = |x, color| {
+            Some(FilmSample {
+                x,
+                y: 0.5,
+                color: Vector::splat(color),
+            })
+        };
+        film.add_pass(&[sample(0.0, 1.0), sample(1.99, 3.0)]);
+        film.add_pass(&[sample(0.99, 2.0), None]);
+
+        assert_eq!(film.pixels(), vec![Vector::splat(1.5), Vector::splat(3.0)]);
+    }
+}
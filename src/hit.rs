@@ -1,25 +1,43 @@
-use crate::{materials::MaterialRef, ray::Ray, Real, Vector};
+use crate::{materials::MaterialRef, ray::Ray, shapes::ShapeRef, Real, Vector};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Hit {
     pub t: Real,
     normal: Vector,
+    uv: (Real, Real),
     pub material: MaterialRef,
+    pub shape: ShapeRef,
 }
 
 pub struct HitInfo {
     pub point: Vector,
     pub normal: Vector,
+    pub uv: (Real, Real),
     pub front_face: bool,
     pub material: MaterialRef,
 }
 
 impl Hit {
-    pub fn new(t: Real, normal: impl Into<Vector>, material: MaterialRef) -> Hit {
+    pub fn new(t: Real, normal: impl Into<Vector>, uv: (Real, Real), material: MaterialRef) -> Hit {
         Hit {
             t,
             normal: normal.into(),
+            uv,
             material,
+            shape: 0,
+        }
+    }
+
+    // Shapes don't know where they are in the scene, the BVH tells the hit once it finds it
+    pub fn on_shape(self, shape: ShapeRef) -> Hit {
+        Hit { shape, ..self }
+    }
+
+    // Same hit seen from the other side of the surface
+    pub fn flipped(self) -> Hit {
+        Hit {
+            normal: -self.normal,
+            ..self
         }
     }
 
@@ -37,6 +55,7 @@ impl Hit {
         HitInfo {
             point: ray.point(self.t),
             normal,
+            uv: self.uv,
             material: self.material,
             front_face,
         }
@@ -0,0 +1,548 @@
+use std::ops::{Add, Mul};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bdpt::Bidirectional,
+    config::Config,
+    debug::DebugView,
+    hit::{Hit, HitInfo},
+    lights::LightSample,
+    materials::{dielectric_split, random_unit_vector, reflect, Material, D_LINE},
+    photon_map::{PhotonMapper, PhotonSettings},
+    ray::Ray,
+    sampler::Sampler,
+    spectrum::Wavelengths,
+    world::World,
+    Real, Vector,
+};
+
+// Paths always get this many bounces before Russian roulette can stop them
+pub const ROULETTE_DEPTH: usize = 3;
+// Whitted rays split in two on every glass surface, so they can't go as deep as the paths
+const WHITTED_DEPTH: usize = 8;
+// Every vertex of the camera path gets joined to every vertex of the light path, the cost grows
+// with the square of the depth
+const BIDIRECTIONAL_DEPTH: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub enum IntegratorKind {
+    #[default]
+    PathTracing,
+    Bidirectional,
+    PhotonMapping(PhotonSettings), // Path tracing, with the caustics from a map of photons
+    DirectLighting,
+    AmbientOcclusion(Real), // Distance past which nothing counts as occluding
+    Whitted,
+    Debug(DebugView),
+}
+
+impl IntegratorKind {
+    pub fn create(self, config: &Config) -> Box<dyn Integrator> {
+        if config.spectral && self.carries_rgb() {
+            eprintln!(
+                "Warning: {self:?} carries red, green and blue, not wavelengths. Only path \
+                 tracing renders spectrally, glass won't split the light"
+            );
+        }
+
+        match self {
+            IntegratorKind::PathTracing => Box::new(PathTracer::new(config.ttl, config.spectral)),
+            IntegratorKind::Bidirectional => {
+                Box::new(Bidirectional::new(config.ttl.min(BIDIRECTIONAL_DEPTH)))
+            }
+            IntegratorKind::PhotonMapping(settings) => {
+                Box::new(PhotonMapper::new(settings, config.ttl))
+            }
+            IntegratorKind::DirectLighting => Box::new(DirectLighting { ttl: config.ttl }),
+            IntegratorKind::AmbientOcclusion(distance) => Box::new(AmbientOcclusion { distance }),
+            IntegratorKind::Whitted => Box::new(Whitted {
+                depth: config.ttl.min(WHITTED_DEPTH),
+            }),
+            IntegratorKind::Debug(view) => Box::new(view),
+        }
+    }
+
+    // Integrators that follow the light around but ignore the spectral mode. The others either
+    // take part in it or don't carry light at all
+    fn carries_rgb(self) -> bool {
+        match self {
+            IntegratorKind::PathTracing
+            | IntegratorKind::AmbientOcclusion(_)
+            | IntegratorKind::Debug(_) => false,
+            IntegratorKind::Bidirectional
+            | IntegratorKind::PhotonMapping(_)
+            | IntegratorKind::DirectLighting
+            | IntegratorKind::Whitted => true,
+        }
+    }
+}
+
+pub trait Integrator: Sync {
+    // Work done once per render before any ray is traced, like shooting photons
+    fn prepare(&mut self, _world: &World, _seed: u64) {}
+
+    // Light arriving to the origin of the ray from its direction
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance;
+}
+
+// Light found by an integrator, split by the number of bounces it took to reach the camera
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Radiance {
+    pub emission: Vector, // Lights and sky seen straight away
+    pub direct: Vector,   // One bounce
+    pub indirect: Vector, // Anything after that
+    // What the ray hit first, for the passes. None when it escaped
+    pub first_hit: Option<Hit>,
+}
+
+impl Radiance {
+    pub fn gather(&mut self, bounces: usize, light: Vector) {
+        match bounces {
+            0 => self.emission += light,
+            1 => self.direct += light,
+            _ => self.indirect += light,
+        }
+    }
+
+    // The same light reached through one more bounce
+    fn bounced(self) -> Radiance {
+        Radiance {
+            emission: Vector::ZERO,
+            direct: self.emission,
+            indirect: self.direct + self.indirect,
+            first_hit: None,
+        }
+    }
+
+    pub fn total(&self) -> Vector {
+        self.emission + self.direct + self.indirect
+    }
+}
+
+// Integrators that don't follow light around report everything as emission
+impl From<Vector> for Radiance {
+    fn from(emission: Vector) -> Self {
+        Radiance {
+            emission,
+            ..Default::default()
+        }
+    }
+}
+
+impl Add for Radiance {
+    type Output = Radiance;
+
+    fn add(self, other: Radiance) -> Radiance {
+        Radiance {
+            emission: self.emission + other.emission,
+            direct: self.direct + other.direct,
+            indirect: self.indirect + other.indirect,
+            first_hit: self.first_hit.or(other.first_hit),
+        }
+    }
+}
+
+impl<T: Into<Vector>> Mul<T> for Radiance {
+    type Output = Radiance;
+
+    fn mul(self, weight: T) -> Radiance {
+        let weight = weight.into();
+        Radiance {
+            emission: self.emission * weight,
+            direct: self.direct * weight,
+            indirect: self.indirect * weight,
+            first_hit: self.first_hit,
+        }
+    }
+}
+
+// Light reaching the point straight from one of the lights, picked at random
+fn direct_lighting(
+    world: &World,
+    ray: &Ray,
+    hit: &HitInfo,
+    material: &Material,
+    sampler: &mut dyn Sampler,
+) -> Vector {
+    match world.lights.sample(&world.scene, hit.point, sampler) {
+        Some(sample) => light_contribution(
+            world,
+            ray,
+            hit,
+            material,
+            &sample,
+            sampler,
+            &Wavelengths::RGB,
+        ),
+        None => Vector::ZERO,
+    }
+}
+
+// Same, for integrators whose scattered rays can also find the lights with a shape. Both ways
+// are weighted by how likely each was to find the light, like for the sky
+pub fn weighted_direct_lighting(
+    world: &World,
+    ray: &Ray,
+    hit: &HitInfo,
+    material: &Material,
+    sampler: &mut dyn Sampler,
+    wavelengths: &Wavelengths,
+) -> Vector {
+    let sample = match world.lights.sample(&world.scene, hit.point, sampler) {
+        Some(sample) => sample,
+        None => return Vector::ZERO,
+    };
+
+    let weight = if sample.delta {
+        1.0
+    } else {
+        power_heuristic(sample.pdf, material.pdf(ray, hit, sample.direction))
+    };
+    light_contribution(world, ray, hit, material, &sample, sampler, wavelengths) * weight
+}
+
+// Each color becomes a spectrum on its own, the spectrum of a product isn't the product of the
+// spectra
+pub fn light_contribution(
+    world: &World,
+    ray: &Ray,
+    hit: &HitInfo,
+    material: &Material,
+    sample: &LightSample,
+    sampler: &mut dyn Sampler,
+    wavelengths: &Wavelengths,
+) -> Vector {
+    let reflected = match material.eval(ray, hit, sample.direction) {
+        Some(reflected) => reflected,
+        None => return Vector::ZERO,
+    };
+    let transmittance = world.transmittance(hit.point, sample.direction, sample.distance, sampler);
+    if transmittance == 0.0 {
+        return Vector::ZERO;
+    }
+    wavelengths.color(reflected) * wavelengths.color(sample.radiance) * transmittance / sample.pdf
+}
+
+// Weight of a sample from one of two ways of picking the same directions, by how likely each
+// was to pick it (power heuristic)
+pub fn power_heuristic(pdf: Real, other_pdf: Real) -> Real {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 {
+        return 0.0;
+    }
+    a / (a + b)
+}
+
+// Light reaching the point from a direction picked towards the bright parts of the sky, for
+// the environments that can be importance sampled. Scattered rays can find the same light, so
+// each is weighted by how likely it was to find it compared to the other
+pub fn sky_lighting(
+    world: &World,
+    ray: &Ray,
+    hit: &HitInfo,
+    material: &Material,
+    sampler: &mut dyn Sampler,
+    wavelengths: &Wavelengths,
+) -> Vector {
+    let sample = match world.environment.sample(sampler) {
+        Some(sample) => sample,
+        None => return Vector::ZERO,
+    };
+
+    let reflected = match material.eval(ray, hit, sample.direction) {
+        Some(reflected) => reflected,
+        None => return Vector::ZERO,
+    };
+    let transmittance = world.transmittance(hit.point, sample.direction, Real::INFINITY, sampler);
+    if transmittance == 0.0 {
+        return Vector::ZERO;
+    }
+
+    let weight = power_heuristic(sample.pdf, material.pdf(ray, hit, sample.direction));
+    let sky = wavelengths.color(sample.radiance) * transmittance;
+    wavelengths.color(reflected) * sky / sample.pdf * weight
+}
+
+// Weight of the sky seen by a ray that escaped, scattered with the given density. Mirrors and
+// glass have no density, the sky can't be sampled towards their exact directions
+pub fn escaped_weight(world: &World, direction: Vector, scatter_pdf: Option<Real>) -> Real {
+    match (world.environment.pdf(direction), scatter_pdf) {
+        (Some(sky_pdf), Some(scatter_pdf)) => power_heuristic(scatter_pdf, sky_pdf),
+        _ => 1.0,
+    }
+}
+
+// Lets integrators built on the path tracer change what it gathers along the way
+pub trait PathHook {
+    // Whether the light given off by the surface the path just found counts
+    fn keeps_emission(&self, _world: &World, _hit: &Hit) -> bool {
+        true
+    }
+
+    // Called on every surface once its lights are gathered. Returns more light reaching the
+    // surface, through at least one more bounce
+    fn visit(&mut self, _ray: &Ray, _hit: &HitInfo, _material: &Material) -> Vector {
+        Vector::ZERO
+    }
+}
+
+// The plain path tracer changes nothing
+impl PathHook for () {}
+
+pub struct PathTracer {
+    ttl: usize,
+    spectral: bool,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        let wavelengths = if self.spectral {
+            Wavelengths::sample(sampler.get_1d())
+        } else {
+            Wavelengths::RGB
+        };
+        self.trace(ray, world, sampler, wavelengths, &mut ())
+    }
+}
+
+impl PathTracer {
+    pub fn new(ttl: usize, spectral: bool) -> Self {
+        Self { ttl, spectral }
+    }
+
+    // Follows the path of the light backwards until it escapes to the sky. The path is cut off
+    // at ttl bounces, and randomly before that once it carries little light (Russian roulette).
+    // The light is carried as the wavelengths say, and turned back into colors at the end
+    pub fn trace(
+        &self,
+        ray: &Ray,
+        world: &World,
+        sampler: &mut dyn Sampler,
+        mut wavelengths: Wavelengths,
+        hook: &mut dyn PathHook,
+    ) -> Radiance {
+        let mut ray = *ray;
+        let mut radiance = Radiance::default();
+        // How much of the light at the current ray reaches the camera
+        let mut throughput = Vector::ONE;
+        // Density of the last bounce picking the current ray, None from the camera and mirrors
+        let mut scatter_pdf = None;
+
+        for depth in 0..self.ttl {
+            let h = match world.sample_hit(&ray, sampler) {
+                Some(h) => h,
+                None => {
+                    let weight = escaped_weight(world, ray.direction, scatter_pdf);
+                    let sky = wavelengths.color(world.sky(ray.direction));
+                    radiance.gather(depth, throughput * sky * weight);
+                    break;
+                }
+            };
+
+            let info = h.get_hit_info(&ray);
+            if depth == 0 {
+                radiance.first_hit = Some(h);
+            }
+            let mut material = world.scene.materials[info.material];
+            if hook.keeps_emission(world, &h) {
+                // The last bounce also sent a ray towards the lights, which could have found this
+                let weight = match scatter_pdf {
+                    Some(scatter_pdf) => {
+                        let light_pdf = world.lights.pdf(&world.scene, ray.origin, h.shape, &info);
+                        power_heuristic(scatter_pdf, light_pdf)
+                    }
+                    None => 1.0,
+                };
+                let emitted = wavelengths.color(material.emitted());
+                radiance.gather(depth, throughput * emitted * weight);
+            }
+
+            if let Some(hero) = wavelengths.hero().filter(|_| material.is_dispersive()) {
+                throughput *= wavelengths.terminate_secondary();
+                material = material.at_wavelength(hero);
+            }
+
+            if !material.is_specular() {
+                let direct =
+                    weighted_direct_lighting(world, &ray, &info, &material, sampler, &wavelengths)
+                        + sky_lighting(world, &ray, &info, &material, sampler, &wavelengths);
+                radiance.gather(depth + 1, throughput * direct);
+            }
+            let more = hook.visit(&ray, &info, &material);
+            radiance.gather(depth + 2, throughput * more);
+
+            match material.scatter(&ray, &info, sampler) {
+                Some((scattered, attenuation)) => {
+                    scatter_pdf = (!material.is_specular())
+                        .then(|| material.pdf(&ray, &info, scattered.direction));
+                    throughput *= wavelengths.color(attenuation);
+                    ray = scattered;
+                }
+                None => break,
+            }
+
+            if depth >= ROULETTE_DEPTH {
+                // Keep the estimate unbiased by boosting the paths that survive
+                let survival = throughput.max_element().min(0.95);
+                if sampler.get_1d() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+
+        // Whatever light is left past the cut off is lost
+        Radiance {
+            emission: wavelengths.to_rgb(radiance.emission),
+            direct: wavelengths.to_rgb(radiance.direct),
+            indirect: wavelengths.to_rgb(radiance.indirect),
+            first_hit: radiance.first_hit,
+        }
+    }
+}
+
+// Only the light that reaches the first rough surface straight from a light or the sky.
+// Mirrors and glass are followed until they reach one
+pub struct DirectLighting {
+    ttl: usize,
+}
+
+impl Integrator for DirectLighting {
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        let mut ray = *ray;
+        let mut radiance = Radiance::default();
+        let mut throughput = Vector::ONE;
+
+        for depth in 0..self.ttl {
+            let h = match world.sample_hit(&ray, sampler) {
+                Some(h) => h,
+                None => {
+                    radiance.gather(depth, throughput * world.sky(ray.direction));
+                    return radiance;
+                }
+            };
+
+            let info = h.get_hit_info(&ray);
+            if depth == 0 {
+                radiance.first_hit = Some(h);
+            }
+            let material = &world.scene.materials[info.material];
+            radiance.gather(depth, throughput * material.emitted());
+
+            let scattered = material.scatter(&ray, &info, sampler);
+
+            if !material.is_specular() {
+                let direct = direct_lighting(world, &ray, &info, material, sampler)
+                    + sky_lighting(world, &ray, &info, material, sampler, &Wavelengths::RGB);
+                radiance.gather(depth + 1, throughput * direct);
+
+                // The sky is too big to pick points on, look for it with a scattered ray too
+                if let Some((scattered, attenuation)) = scattered {
+                    if world.sample_hit(&scattered, sampler).is_none() {
+                        let scatter_pdf = material.pdf(&ray, &info, scattered.direction);
+                        let weight = escaped_weight(world, scattered.direction, Some(scatter_pdf));
+                        let sky = world.sky(scattered.direction) * weight;
+                        radiance.gather(depth + 1, throughput * attenuation * sky);
+                    }
+                }
+                return radiance;
+            }
+
+            match scattered {
+                Some((scattered, attenuation)) => {
+                    throughput *= attenuation;
+                    ray = scattered;
+                }
+                None => return radiance,
+            }
+        }
+
+        radiance
+    }
+}
+
+// White where the sky is visible around the first hit and black where something is close by
+pub struct AmbientOcclusion {
+    distance: Real,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        let hit = match world.sample_hit(ray, sampler) {
+            Some(hit) => hit,
+            None => return Vector::ONE.into(),
+        };
+        let info = hit.get_hit_info(ray);
+
+        let probe = Ray::new(info.point, info.normal + random_unit_vector(sampler));
+        let color = match world.sample_hit(&probe, sampler) {
+            Some(h) if h.t < self.distance => Vector::ZERO,
+            _ => Vector::ONE,
+        };
+        Radiance {
+            first_hit: Some(hit),
+            ..color.into()
+        }
+    }
+}
+
+// Classic recursive ray tracing: perfect reflections and refractions, light sampling on
+// everything else and the sky as an ambient term
+pub struct Whitted {
+    depth: usize,
+}
+
+impl Whitted {
+    fn trace(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler, depth: usize) -> Radiance {
+        if depth == 0 {
+            return Radiance::default();
+        }
+
+        let hit = match world.sample_hit(ray, sampler) {
+            Some(hit) => hit,
+            None => return world.sky(ray.direction).into(),
+        };
+        let info = hit.get_hit_info(ray);
+        let material = &world.scene.materials[info.material];
+        // The bounces below aren't first hits anymore
+        let emitted = Radiance {
+            first_hit: Some(hit),
+            ..material.emitted().into()
+        };
+
+        match *material {
+            Material::Metal(albedo, _) => {
+                let reflected = Ray::new(info.point, reflect(ray.direction, info.normal));
+                emitted + self.trace(&reflected, world, sampler, depth - 1).bounced() * albedo
+            }
+            Material::Dielectric(ior) => {
+                let (reflected, refracted, reflectance) =
+                    dielectric_split(ray, &info, ior.at(D_LINE));
+                let reflected =
+                    self.trace(&Ray::new(info.point, reflected), world, sampler, depth - 1);
+                let refracted = match refracted {
+                    Some(refracted) if reflectance < 1.0 => {
+                        self.trace(&Ray::new(info.point, refracted), world, sampler, depth - 1)
+                    }
+                    _ => Radiance::default(),
+                };
+                emitted
+                    + (reflected * Vector::splat(reflectance)
+                        + refracted * Vector::splat(1.0 - reflectance))
+                    .bounced()
+            }
+            _ => {
+                let direct = direct_lighting(world, ray, &info, material, sampler)
+                    + material.albedo() * world.sky(info.normal);
+                emitted + Radiance::from(direct).bounced()
+            }
+        }
+    }
+}
+
+impl Integrator for Whitted {
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        self.trace(ray, world, sampler, self.depth)
+    }
+}
@@ -0,0 +1,325 @@
+use std::f64::consts::PI;
+
+use glam::DQuat;
+
+use crate::{aabb::Aabb, Real, Vector};
+
+// Directions within an angle of an axis, as the cosine of the angle
+#[derive(Debug, Copy, Clone)]
+pub struct Cone {
+    pub axis: Vector,
+    pub cos_theta: Real,
+}
+
+impl Cone {
+    pub fn sphere() -> Self {
+        Self {
+            axis: Vector::Z,
+            cos_theta: -1.0,
+        }
+    }
+
+    // Smallest cone holding both (pbrt's DirectionCone::Union)
+    fn union(self, other: Self) -> Self {
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = self.axis.angle_between(other.axis);
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return self;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return other;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        let pivot = self.axis.cross(other.axis);
+        if theta_o >= PI || pivot.length_squared() == 0.0 {
+            return Self::sphere();
+        }
+        // Turn the axis of the first cone towards the second until it covers both
+        let rotation = DQuat::from_axis_angle(pivot.normalize(), theta_o - theta_a);
+        Self {
+            axis: rotation * self.axis,
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+// Where a group of lights is, how much they emit and where to (Conty Estevez and Kulla, 2018).
+// The normals of the emitters are within `normals`, and they shine up to `cos_theta_e` past them
+#[derive(Debug, Copy, Clone)]
+pub struct LightBounds {
+    pub bounds: Aabb,
+    pub power: Real,
+    pub normals: Cone,
+    pub cos_theta_e: Real,
+    pub two_sided: bool,
+}
+
+// cos(max(0, a - b)) from the sines and cosines of both angles
+fn cos_sub_clamped(sin_a: Real, cos_a: Real, sin_b: Real, cos_b: Real) -> Real {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+fn sin_from_cos(cos: Real) -> Real {
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+impl LightBounds {
+    fn union(&self, other: &Self) -> Self {
+        Self {
+            bounds: self.bounds.surrounding_box(&other.bounds),
+            power: self.power + other.power,
+            normals: self.normals.union(other.normals),
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    fn center(&self) -> Vector {
+        (self.bounds.min + self.bounds.max) / 2.0
+    }
+
+    // Rough estimate of the light reaching the point: the power, over the squared distance, and
+    // gone if no emitter in the group can face the point
+    fn importance(&self, point: Vector) -> Real {
+        let center = self.center();
+        let diagonal = self.bounds.max - self.bounds.min;
+        // Don't let points inside or close to the group blow up
+        let distance_squared = point.distance_squared(center).max(diagonal.length() / 2.0);
+
+        let towards_point = (point - center).normalize_or_zero();
+        let mut cos_theta_w = self.normals.axis.dot(towards_point);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+
+        // Angle the bounds take up as seen from the point
+        let radius_squared = diagonal.length_squared() / 4.0;
+        let cos_theta_b = if point.distance_squared(center) < radius_squared {
+            -1.0
+        } else {
+            (1.0 - radius_squared / point.distance_squared(center))
+                .max(0.0)
+                .sqrt()
+        };
+
+        // Smallest angle between a normal and a direction from the group to the point
+        let cos_theta_x = cos_sub_clamped(
+            sin_from_cos(cos_theta_w),
+            cos_theta_w,
+            sin_from_cos(self.normals.cos_theta),
+            self.normals.cos_theta,
+        );
+        let cos_theta = cos_sub_clamped(
+            sin_from_cos(cos_theta_x),
+            cos_theta_x,
+            sin_from_cos(cos_theta_b),
+            cos_theta_b,
+        );
+        if cos_theta <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        self.power * cos_theta / distance_squared
+    }
+}
+
+enum Node {
+    Leaf(usize),     // Index of the light
+    Interior(usize), // Index of the second child, the first one follows
+}
+
+// Binary tree over the lights, descended by picking the child more likely to light the point
+pub struct LightTree {
+    nodes: Vec<(LightBounds, Node)>,
+    // Branches from the root to each light, as bits from the lowest, 1 for the second child
+    paths: Vec<Option<u64>>,
+}
+
+impl LightTree {
+    // Lights without bounds don't go in the tree
+    pub fn new(lights: &[Option<LightBounds>]) -> Self {
+        let mut bounded = lights
+            .iter()
+            .enumerate()
+            .filter_map(|(index, bounds)| Some((index, (*bounds)?)))
+            .collect::<Vec<_>>();
+
+        let mut tree = Self {
+            nodes: Vec::new(),
+            paths: vec![None; lights.len()],
+        };
+        if !bounded.is_empty() {
+            tree.build(&mut bounded, 0, 0);
+        }
+        tree
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], path: u64, depth: u32) -> LightBounds {
+        let node = self.nodes.len();
+        if let [(index, bounds)] = *lights {
+            self.nodes.push((bounds, Node::Leaf(index)));
+            self.paths[index] = Some(path);
+            return bounds;
+        }
+
+        // Split at the median along the axis the lights spread the most
+        let centers = lights
+            .iter()
+            .map(|(_, bounds)| bounds.center())
+            .collect::<Vec<_>>();
+        let min = centers
+            .iter()
+            .fold(Vector::splat(Real::INFINITY), |a, b| a.min(*b));
+        let max = centers
+            .iter()
+            .fold(Vector::splat(Real::NEG_INFINITY), |a, b| a.max(*b));
+        let extent = max - min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let middle = lights.len() / 2;
+        lights.select_nth_unstable_by(middle, |(a_index, a), (b_index, b)| {
+            a.center()[axis]
+                .total_cmp(&b.center()[axis])
+                .then(a_index.cmp(b_index))
+        });
+
+        // Filled in once the children know their bounds
+        self.nodes.push((lights[0].1, Node::Interior(0)));
+        let (first, second) = lights.split_at_mut(middle);
+        let first = self.build(first, path, depth + 1);
+        let second_node = self.nodes.len();
+        // Median splits keep the tree balanced, far from the 64 levels the paths can hold
+        let second = self.build(second, path | 1 << depth, depth + 1);
+
+        let bounds = first.union(&second);
+        self.nodes[node] = (bounds, Node::Interior(second_node));
+        bounds
+    }
+
+    fn children(&self, node: usize) -> Option<(usize, usize)> {
+        match self.nodes[node].1 {
+            Node::Interior(second) => Some((node + 1, second)),
+            Node::Leaf(_) => None,
+        }
+    }
+
+    // Chance of going down the first child from the point, None if neither lights it
+    fn p_first(&self, (first, second): (usize, usize), point: Vector) -> Option<Real> {
+        let importance_first = self.nodes[first].0.importance(point);
+        let importance_second = self.nodes[second].0.importance(point);
+        let total = importance_first + importance_second;
+        if total == 0.0 {
+            return None;
+        }
+        Some(importance_first / total)
+    }
+
+    // Light and the probability of picking it, None if no light can reach the point
+    pub fn sample(&self, point: Vector, mut u: Real) -> Option<(usize, Real)> {
+        if self.is_empty() || self.nodes[0].0.importance(point) == 0.0 {
+            return None;
+        }
+
+        let mut node = 0;
+        let mut pmf = 1.0;
+        while let Some(children) = self.children(node) {
+            let p_first = self.p_first(children, point)?;
+            // Reuse the random number, stretched over the part it fell in
+            if u < p_first {
+                u = (u / p_first).min(1.0 - Real::EPSILON);
+                pmf *= p_first;
+                node = children.0;
+            } else {
+                u = ((u - p_first) / (1.0 - p_first)).min(1.0 - Real::EPSILON);
+                pmf *= 1.0 - p_first;
+                node = children.1;
+            }
+        }
+
+        match self.nodes[node].1 {
+            Node::Leaf(index) => Some((index, pmf)),
+            Node::Interior(_) => None,
+        }
+    }
+
+    // Probability of `sample` picking the light from the point
+    pub fn pmf(&self, point: Vector, light: usize) -> Real {
+        let mut path = match self.paths[light] {
+            Some(path) => path,
+            None => return 0.0,
+        };
+        if self.nodes[0].0.importance(point) == 0.0 {
+            return 0.0;
+        }
+
+        let mut node = 0;
+        let mut pmf = 1.0;
+        while let Some(children) = self.children(node) {
+            let p_first = match self.p_first(children, point) {
+                Some(p_first) => p_first,
+                None => return 0.0,
+            };
+            if path & 1 == 0 {
+                pmf *= p_first;
+                node = children.0;
+            } else {
+                pmf *= 1.0 - p_first;
+                node = children.1;
+            }
+            path >>= 1;
+        }
+        pmf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_light(position: Vector, power: Real) -> Option<LightBounds> {
+        Some(LightBounds {
+            bounds: Aabb::new(position, position),
+            power,
+            normals: Cone::sphere(),
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+
+    #[test]
+    fn picks_the_close_lights() {
+        let lights = (0..8)
+            .map(|i| point_light(Vector::new(i as Real * 10.0, 0.0, 0.0), 1.0))
+            .chain([None])
+            .collect::<Vec<_>>();
+        let tree = LightTree::new(&lights);
+        let point = Vector::new(31.0, 1.0, 0.0);
+
+        let mut total = 0.0;
+        for light in 0..lights.len() {
+            total += tree.pmf(point, light);
+        }
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(tree.pmf(point, 3) > 10.0 * tree.pmf(point, 7));
+        assert_eq!(tree.pmf(point, 8), 0.0);
+
+        for u in [0.1, 0.5, 0.9] {
+            let (light, pmf) = tree.sample(point, u).unwrap();
+            assert!((tree.pmf(point, light) - pmf).abs() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aabb::Aabb,
+    distribution::AliasTable,
+    hit::HitInfo,
+    light_tree::{Cone, LightBounds, LightTree},
+    luminance,
+    sampler::Sampler,
+    scene::Scene,
+    shapes::{Shape, ShapeKind, ShapeRef},
+    Real, Vector,
+};
+
+// Lights that shine from a single point or direction. Rays can never hit them, they are only
+// found by sampling them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Light {
+    Point(Vector, Vector), // Position and intensity
+    // Position, direction it points to, intensity, and the angles in degrees where the falloff
+    // starts and where the light ends
+    Spot(Vector, Vector, Vector, Real, Real),
+    Directional(Vector, Vector), // Direction towards the light and irradiance
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub enum LightSampling {
+    Uniform,
+    Power, // Proportional to how much light each one gives off
+    // Proportional to an estimate of the light reaching the point, from a tree over the lights
+    #[default]
+    Tree,
+}
+
+enum Selector {
+    Uniform,
+    Power(AliasTable),
+    // Directional lights are everywhere, they are picked apart from the tree
+    Tree(LightTree, Vec<usize>),
+}
+
+// Shapes with an emissive material that we know how to pick points on, and the lights of the
+// scene after them
+pub struct Lights {
+    emitters: Vec<ShapeRef>,
+    count: usize,
+    // Light of each shape, for the emitters
+    of_shape: Vec<Option<usize>>,
+    selector: Selector,
+    // Picks the lights by their power for the paths that start from them
+    emission: AliasTable,
+}
+
+pub struct LightSample {
+    pub direction: Vector,
+    pub distance: Real, // Infinite for directional lights
+    // Light arriving at the point. For the lights in a single direction, already integrated
+    pub radiance: Vector,
+    // Probability density of having picked this light and direction, over solid angle. Just
+    // the probability of picking the light for the lights in a single direction
+    pub pdf: Real,
+    pub delta: bool, // From a single direction, scattered rays can't find it
+}
+
+// Point on one of the lights for a path to start from
+pub struct LightOrigin {
+    pub light: usize,
+    pub point: Vector,
+    pub normal: Option<Vector>, // None for the lights at a single point
+    // Probability density of having picked this light and point, over the area of the light.
+    // Just the probability of picking the light for the lights at a single point
+    pub pdf: Real,
+}
+
+fn smoothstep(edge0: Real, edge1: Real, x: Real) -> Real {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Direction around the axis, uniformly within the angle
+fn sample_cone(axis: Vector, cos_theta_max: Real, (u, v): (Real, Real)) -> Vector {
+    let cos_theta = 1.0 - u * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    let (tangent, bitangent) = axis.any_orthonormal_pair();
+    (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + axis * cos_theta
+}
+
+fn cone_solid_angle(cos_theta_max: Real) -> Real {
+    2.0 * PI * (1.0 - cos_theta_max)
+}
+
+impl Light {
+    // As seen from `point`
+    pub fn sample(&self, point: Vector) -> Option<LightSample> {
+        let (to_light, intensity) = match *self {
+            Light::Point(position, intensity) => (position - point, intensity),
+            Light::Spot(position, ..) => {
+                let to_light = position - point;
+                let intensity = self.emitted(-to_light.normalize());
+                if intensity == Vector::ZERO {
+                    return None;
+                }
+                (to_light, intensity)
+            }
+            Light::Directional(direction, irradiance) => {
+                return Some(LightSample {
+                    direction: direction.normalize(),
+                    distance: Real::INFINITY,
+                    radiance: irradiance,
+                    pdf: 1.0,
+                    delta: true,
+                });
+            }
+        };
+
+        // Spreads out with the square of the distance
+        let distance_squared = to_light.length_squared();
+        let distance = distance_squared.sqrt();
+        Some(LightSample {
+            direction: to_light / distance,
+            distance,
+            radiance: intensity / distance_squared,
+            pdf: 1.0,
+            delta: true,
+        })
+    }
+
+    // Intensity towards the direction, for the lights at a single point
+    fn emitted(&self, direction: Vector) -> Vector {
+        match *self {
+            Light::Point(_, intensity) => intensity,
+            Light::Spot(_, axis, intensity, falloff_start, end) => {
+                let falloff = smoothstep(
+                    end.to_radians().cos(),
+                    falloff_start.to_radians().cos(),
+                    axis.normalize().dot(direction),
+                );
+                intensity * falloff
+            }
+            Light::Directional(..) => Vector::ZERO,
+        }
+    }
+
+    // Direction for light leaving it and its density over solid angle, spots only send it
+    // within their cone
+    fn sample_direction(&self, uv: (Real, Real)) -> Option<(Vector, Real)> {
+        match *self {
+            Light::Point(..) => Some((sample_cone(Vector::Z, -1.0, uv), 1.0 / (4.0 * PI))),
+            Light::Spot(_, axis, _, _, end) => {
+                let cos_end = end.to_radians().cos();
+                let direction = sample_cone(axis.normalize(), cos_end, uv);
+                Some((direction, 1.0 / cone_solid_angle(cos_end)))
+            }
+            Light::Directional(..) => None,
+        }
+    }
+
+    fn direction_pdf(&self, direction: Vector) -> Real {
+        match *self {
+            Light::Point(..) => 1.0 / (4.0 * PI),
+            Light::Spot(_, axis, _, _, end) => {
+                let cos_end = end.to_radians().cos();
+                if axis.normalize().dot(direction) < cos_end {
+                    return 0.0;
+                }
+                1.0 / cone_solid_angle(cos_end)
+            }
+            Light::Directional(..) => 0.0,
+        }
+    }
+
+    // Where it is and where it shines to, None for the directional lights
+    fn bounds(&self) -> Option<LightBounds> {
+        let (position, normals, cos_theta_e) = match *self {
+            Light::Point(position, _) => (position, Cone::sphere(), 0.0),
+            Light::Spot(position, direction, _, falloff_start, end) => {
+                let (start, end) = (falloff_start.to_radians(), end.to_radians());
+                let normals = Cone {
+                    axis: direction.normalize(),
+                    cos_theta: start.cos(),
+                };
+                (position, normals, (end - start).max(0.0).cos())
+            }
+            Light::Directional(..) => return None,
+        };
+
+        Some(LightBounds {
+            bounds: Aabb::new(position, position),
+            power: self.power(0.0),
+            normals,
+            cos_theta_e,
+            two_sided: false,
+        })
+    }
+
+    // Light it gives off, the directional ones over a disk as wide as the scene
+    fn power(&self, scene_radius: Real) -> Real {
+        match *self {
+            Light::Point(_, intensity) => 4.0 * PI * luminance(intensity),
+            Light::Spot(_, _, intensity, falloff_start, end) => {
+                let cos_middle = (falloff_start.to_radians().cos() + end.to_radians().cos()) / 2.0;
+                2.0 * PI * luminance(intensity) * (1.0 - cos_middle)
+            }
+            Light::Directional(_, irradiance) => {
+                PI * scene_radius * scene_radius * luminance(irradiance)
+            }
+        }
+    }
+}
+
+// Emissive shapes glow on both sides, over all their area
+fn shape_bounds(shape: &Shape, radiance: Vector) -> Option<LightBounds> {
+    let area = shape.kind.area()?;
+    let normals = match shape.kind {
+        ShapeKind::Sphere(..) => Cone::sphere(),
+        _ => Cone {
+            axis: shape.kind.sample(0.5, 0.5)?.1,
+            cos_theta: 1.0,
+        },
+    };
+
+    Some(LightBounds {
+        bounds: Aabb::from_shape(&shape.kind)?,
+        power: 2.0 * PI * area * luminance(radiance),
+        normals,
+        cos_theta_e: 0.0,
+        two_sided: true,
+    })
+}
+
+impl Lights {
+    pub fn new(scene: &Scene, sampling: LightSampling) -> Self {
+        let emitters = scene
+            .shapes
+            .iter()
+            .enumerate()
+            .filter(|(_, shape)| {
+                scene.materials[shape.material].emitted() != Vector::ZERO
+                    && shape.kind.area().is_some()
+            })
+            .map(|(shape_ref, _)| shape_ref)
+            .collect::<Vec<_>>();
+
+        let mut of_shape = vec![None; scene.shapes.len()];
+        for (light, shape) in emitters.iter().enumerate() {
+            of_shape[*shape] = Some(light);
+        }
+
+        let bounds = emitters
+            .iter()
+            .map(|shape| {
+                let shape = &scene.shapes[*shape];
+                shape_bounds(shape, scene.materials[shape.material].emitted())
+            })
+            .chain(scene.lights.iter().map(Light::bounds))
+            .collect::<Vec<_>>();
+
+        let selector = match sampling {
+            LightSampling::Uniform => Selector::Uniform,
+            LightSampling::Power => {
+                let scene_radius = Aabb::from_shapes(&scene.shapes)
+                    .map_or(1.0, |aabb| (aabb.max - aabb.min).length() / 2.0);
+                let powers = bounds
+                    .iter()
+                    .take(emitters.len())
+                    .map(|bounds| bounds.map_or(0.0, |bounds| bounds.power))
+                    .chain(scene.lights.iter().map(|light| light.power(scene_radius)))
+                    .collect::<Vec<_>>();
+                Selector::Power(AliasTable::new(&powers))
+            }
+            LightSampling::Tree => {
+                let directional = (emitters.len()..bounds.len())
+                    .filter(|light| bounds[*light].is_none())
+                    .collect();
+                Selector::Tree(LightTree::new(&bounds), directional)
+            }
+        };
+
+        let powers = bounds
+            .iter()
+            .map(|bounds| bounds.map_or(0.0, |bounds| bounds.power))
+            .collect::<Vec<_>>();
+
+        Self {
+            count: bounds.len(),
+            emitters,
+            of_shape,
+            selector,
+            emission: AliasTable::new(&powers),
+        }
+    }
+
+    // One of the lights for `point` and the probability of picking it
+    fn pick(&self, point: Vector, sampler: &mut dyn Sampler) -> Option<(usize, Real)> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let u = sampler.get_1d();
+        match &self.selector {
+            Selector::Uniform => {
+                let pick = ((u * self.count as Real) as usize).min(self.count - 1);
+                Some((pick, 1.0 / self.count as Real))
+            }
+            Selector::Power(table) => Some(table.sample(u)),
+            Selector::Tree(tree, directional) => {
+                // Every directional light is as likely as the whole tree
+                let branches = directional.len() + usize::from(!tree.is_empty());
+                let branch = ((u * branches as Real) as usize).min(branches - 1);
+                let u = u * branches as Real - branch as Real;
+                match directional.get(branch) {
+                    Some(light) => Some((*light, 1.0 / branches as Real)),
+                    None => {
+                        let (light, pmf) = tree.sample(point, u)?;
+                        Some((light, pmf / branches as Real))
+                    }
+                }
+            }
+        }
+    }
+
+    fn pmf(&self, point: Vector, light: usize) -> Real {
+        match &self.selector {
+            Selector::Uniform => 1.0 / self.count as Real,
+            Selector::Power(table) => table.pmf(light),
+            Selector::Tree(tree, directional) => {
+                let branches = directional.len() + usize::from(!tree.is_empty());
+                tree.pmf(point, light) / branches as Real
+            }
+        }
+    }
+
+    // Picks one of the lights, and a point on it for the shapes, as seen from `point`
+    pub fn sample(
+        &self,
+        scene: &Scene,
+        point: Vector,
+        sampler: &mut dyn Sampler,
+    ) -> Option<LightSample> {
+        let (light, pmf) = self.pick(point, sampler)?;
+        let shape = match self.emitters.get(light) {
+            Some(shape) => &scene.shapes[*shape],
+            None => {
+                let sample = scene.lights[light - self.emitters.len()].sample(point)?;
+                return Some(LightSample {
+                    pdf: sample.pdf * pmf,
+                    ..sample
+                });
+            }
+        };
+        let (u, v) = sampler.get_2d();
+        let (light_point, normal) = shape.kind.sample(u, v)?;
+        let area = shape.kind.area()?;
+
+        let to_light = light_point - point;
+        let distance_squared = to_light.length_squared();
+        let distance = distance_squared.sqrt();
+        let direction = to_light / distance;
+        let cos_light = normal.dot(direction).abs();
+        if cos_light < Real::EPSILON {
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: scene.materials[shape.material].emitted(),
+            pdf: area_to_solid_angle(area, distance_squared, cos_light) * pmf,
+            delta: false,
+        })
+    }
+
+    // Density of `sample` picking the point on the shape from `from`, 0 if it isn't a light
+    pub fn pdf(&self, scene: &Scene, from: Vector, shape: ShapeRef, hit: &HitInfo) -> Real {
+        let light = match self.of_shape(shape) {
+            Some(light) => light,
+            None => return 0.0,
+        };
+        let area = match scene.shapes[shape].kind.area() {
+            Some(area) => area,
+            None => return 0.0,
+        };
+
+        let to_light = hit.point - from;
+        let distance_squared = to_light.length_squared();
+        let cos_light = hit.normal.dot(to_light / distance_squared.sqrt()).abs();
+        if cos_light < Real::EPSILON {
+            return 0.0;
+        }
+        area_to_solid_angle(area, distance_squared, cos_light) * self.pmf(from, light)
+    }
+
+    pub fn of_shape(&self, shape: ShapeRef) -> Option<usize> {
+        self.of_shape.get(shape).copied().flatten()
+    }
+
+    // Picks one of the lights by its power, and a point on it, for a path to start from.
+    // Directional lights have nowhere to start from
+    pub fn sample_origin(&self, scene: &Scene, sampler: &mut dyn Sampler) -> Option<LightOrigin> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let (light, pmf) = self.emission.sample(sampler.get_1d());
+        if pmf == 0.0 {
+            return None;
+        }
+        let shape = match self.emitters.get(light) {
+            Some(shape) => &scene.shapes[*shape],
+            None => {
+                let point = match scene.lights[light - self.emitters.len()] {
+                    Light::Point(position, _) | Light::Spot(position, ..) => position,
+                    Light::Directional(..) => return None,
+                };
+                return Some(LightOrigin {
+                    light,
+                    point,
+                    normal: None,
+                    pdf: pmf,
+                });
+            }
+        };
+
+        let (u, v) = sampler.get_2d();
+        let (point, normal) = shape.kind.sample(u, v)?;
+        Some(LightOrigin {
+            light,
+            point,
+            normal: Some(normal),
+            pdf: pmf / shape.kind.area()?,
+        })
+    }
+
+    // Density of `sample_origin` picking any given point of the light
+    pub fn origin_pdf(&self, scene: &Scene, light: usize) -> Real {
+        let pmf = self.emission.pmf(light);
+        match self.emitters.get(light) {
+            Some(shape) => scene.shapes[*shape]
+                .kind
+                .area()
+                .map_or(0.0, |area| pmf / area),
+            None => pmf,
+        }
+    }
+
+    // Direction for the light to leave the origin in, and its density over solid angle. Shapes
+    // send it out of either side, more of it straight out
+    pub fn sample_direction(
+        &self,
+        scene: &Scene,
+        origin: &LightOrigin,
+        (u, v): (Real, Real),
+    ) -> Option<(Vector, Real)> {
+        let normal = match origin.normal {
+            Some(normal) => normal,
+            None => {
+                return scene.lights[origin.light - self.emitters.len()].sample_direction((u, v))
+            }
+        };
+
+        let (side, u) = if u < 0.5 {
+            (1.0, u * 2.0)
+        } else {
+            (-1.0, u * 2.0 - 1.0)
+        };
+        // Cosine weighted, from a disk lifted up to the hemisphere
+        let r = u.sqrt();
+        let phi = 2.0 * PI * v;
+        let (tangent, bitangent) = normal.any_orthonormal_pair();
+        let cos_theta = (1.0 - u).max(0.0).sqrt();
+        let direction =
+            (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * cos_theta) * side;
+        Some((direction, cos_theta / (2.0 * PI)))
+    }
+
+    pub fn direction_pdf(
+        &self,
+        scene: &Scene,
+        light: usize,
+        normal: Option<Vector>,
+        direction: Vector,
+    ) -> Real {
+        match normal {
+            Some(normal) => normal.dot(direction).abs() / (2.0 * PI),
+            None => scene.lights[light - self.emitters.len()].direction_pdf(direction),
+        }
+    }
+
+    // Light leaving the point of the light towards the direction
+    pub fn emitted(
+        &self,
+        scene: &Scene,
+        light: usize,
+        normal: Option<Vector>,
+        direction: Vector,
+    ) -> Vector {
+        match (self.emitters.get(light), normal) {
+            (Some(shape), Some(normal)) if normal.dot(direction) != 0.0 => {
+                scene.materials[scene.shapes[*shape].material].emitted()
+            }
+            (Some(_), _) => Vector::ZERO,
+            (None, _) => scene.lights[light - self.emitters.len()].emitted(direction),
+        }
+    }
+}
+
+// Turns the density over the area of a light into one over the directions around a point
+fn area_to_solid_angle(area: Real, distance_squared: Real, cos_light: Real) -> Real {
+    distance_squared / (cos_light * area)
+}
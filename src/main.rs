@@ -1,23 +1,47 @@
 mod aabb;
+mod adaptive;
+mod aov;
+mod bdpt;
 mod bvh;
 mod camera;
 mod config;
+mod debug;
+mod denoise;
+mod distribution;
+mod environment;
+mod film;
 mod hit;
+mod integrators;
+mod light_tree;
+mod lights;
 mod materials;
+mod photon_map;
+mod polynomial;
+mod progressive;
 mod ray;
 mod raytrace;
+mod regression;
+mod sampler;
 mod scene;
 mod scene_gerenators;
+mod sdf;
 mod shapes;
+mod sky;
+mod spectrum;
+mod volume;
+mod world;
 
 use std::{fs::{File, self}, path::Path};
 use std::time::Instant;
 
 use image::imageops::flip_vertical_in_place;
-use image::ImageBuffer;
+use image::{ImageBuffer, RgbImage};
 use time::OffsetDateTime;
 
+use crate::adaptive::save_sample_map;
+use crate::aov::save_aovs;
 use crate::config::Config;
+use crate::integrators::IntegratorKind;
 use raytrace::*;
 
 use glam::*;
@@ -25,46 +49,85 @@ use glam::*;
 type Vector = DVec3;
 type Real = f64;
 
+// Brightness of a color, with the Rec. 709 weights of each channel
+fn luminance(color: Vector) -> Real {
+    color.dot(Vector::new(0.2126, 0.7152, 0.0722))
+}
+
 fn main() {
-    let config: Config = match File::open("config.json") {
+    let mut config: Config = match File::open("config.json") {
         Ok(file) => serde_json::from_reader(file).unwrap(),
         Err(_) => Default::default(),
     };
 
+    // `--debug <view>` renders one of the debug views instead, e.g. `--debug Normals`
+    let args = std::env::args().collect::<Vec<_>>();
+    if let Some(i) = args.iter().position(|arg| arg == "--debug") {
+        let view = args.get(i + 1).cloned().unwrap_or_default();
+        match serde_json::from_value(serde_json::Value::String(view)) {
+            Ok(view) => config.integrator = IntegratorKind::Debug(view),
+            Err(e) => {
+                // The error lists the views there are
+                eprintln!("Usage: --debug <view>: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
     println!(
         "Parameters: width = {} height = {} samples = {} ttl = {} chunk size = {}",
         config.width, config.height, config.samples, config.ttl, config.chunk_size
     );
 
+    let path = Path::new("./results");
+    if !path.exists() {
+        fs::create_dir(path).unwrap();
+    }
+    // Passes and progressive previews share the name of the beauty image
+    let name = format!("./results/{}_{}", config.scene, OffsetDateTime::now_utc());
+
     let now = Instant::now();
-    let pixels = raytrace(&config);
+    let mut render = raytrace(&config, |render| {
+        print_image(render.pixels.iter().copied(), &config, &name);
+    });
 
-    let rays = config.width * config.height * config.samples;
+    let rays = render.samples.iter().sum::<usize>();
     let millis = now.elapsed().as_millis();
     let rays_sec = rays as f64 / (millis as f64 / 1000.0);
 
-    println!("Time: {millis}ms  Rays per second: {}", rays_sec.floor());
+    println!(
+        "Time: {millis}ms  Rays per second: {}  Seed: {}",
+        rays_sec.floor(),
+        render.seed
+    );
 
-    print_image(pixels, &config);
+    if let (Some(denoiser), Some(aovs)) = (&config.denoiser, &render.aovs) {
+        denoiser.denoise(&mut render.pixels, aovs, config.width, config.height);
+    }
+
+    print_image(render.pixels, &config, &name);
+    if config.adaptive.is_some_and(|adaptive| adaptive.sample_map) {
+        save_sample_map(&render.samples, &config, &name);
+    }
+    match render.aovs {
+        Some(aovs) if config.aovs => save_aovs(&aovs, &config, &name),
+        _ => (),
+    }
+}
+
+fn print_image(pixels: impl IntoIterator<Item = Vector>, config: &Config, name: &str) {
+    to_image(pixels, config)
+        .save(format!("{name}.png"))
+        .unwrap();
 }
 
-fn print_image(pixels: impl IntoIterator<Item = Vector>, config: &Config) {
+fn to_image(pixels: impl IntoIterator<Item = Vector>, config: &Config) -> RgbImage {
     let mut imgbuf = ImageBuffer::new(config.width as u32, config.height as u32);
     for (img_pixel, calculated_pixel) in imgbuf.pixels_mut().zip(pixels) {
-        let color = (calculated_pixel / config.samples as Real) * 255.0;
+        let color = calculated_pixel * 255.0;
         *img_pixel = image::Rgb([color.x as u8, color.y as u8, color.z as u8]);
     }
 
     flip_vertical_in_place(&mut imgbuf);
-    let path = Path::new("./results");
-    if !path.exists() {
-        fs::create_dir(path).unwrap();
-    }
     imgbuf
-        .save(format!(
-            "./results/{}_{}.png",
-            config.scene,
-            OffsetDateTime::now_utc()
-        ))
-        .unwrap();
 }
@@ -1,13 +1,15 @@
+use std::f64::consts::PI;
+
 use crate::hit::HitInfo;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::Real;
 use crate::Vector;
-use nanorand::Rng;
 use serde::{Deserialize, Serialize};
 
 pub type MaterialRef = usize;
 
-fn reflect(a: Vector, b: Vector) -> Vector {
+pub fn reflect(a: Vector, b: Vector) -> Vector {
     a - b * a.dot(b) * 2.0
 }
 
@@ -18,20 +20,48 @@ fn refract(a: Vector, b: Vector, ni_over_nt: Real) -> Vector {
     parallel + perpendicular
 }
 
-fn random_in_unit_sphere() -> Vector {
-    // SPEED Is this the best way?
-    let mut rng = nanorand::tls_rng();
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vector {
+    // The volume grows with the cube of the radius
+    random_unit_vector(sampler) * sampler.get_1d().cbrt()
+}
+
+pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vector {
+    let (u, v) = sampler.get_2d();
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vector::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Wavelength of the helium d line in nanometers, where glasses have their index of refraction
+// measured. Renders in red, green and blue use the index there
+pub const D_LINE: Real = 587.6;
 
-    loop {
-        let v = Vector::new(
-            rng.generate::<Real>(),
-            rng.generate::<Real>(),
-            rng.generate::<Real>(),
-        ) * 2.0
-            - Vector::ONE;
+// Index of refraction of a dielectric, the same for every wavelength or changing with it, which
+// splits white light into colors (dispersion). The formulas take the wavelength in micrometers
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Ior {
+    Constant(Real),
+    // n = a + b / λ²
+    Cauchy { a: Real, b: Real },
+    // n² = 1 + Σ b λ² / (λ² - c), from the glass catalogs
+    Sellmeier { b: [Real; 3], c: [Real; 3] },
+}
 
-        if v.length_squared() < 1.0 {
-            return v;
+impl Ior {
+    pub fn at(&self, wavelength: Real) -> Real {
+        let micrometers = wavelength / 1000.0;
+        let squared = micrometers * micrometers;
+        match *self {
+            Ior::Constant(ior) => ior,
+            Ior::Cauchy { a, b } => a + b / squared,
+            Ior::Sellmeier { b, c } => {
+                let sum = (0..3)
+                    .map(|i| b[i] * squared / (squared - c[i]))
+                    .sum::<Real>();
+                (1.0 + sum).sqrt()
+            }
         }
     }
 }
@@ -39,9 +69,27 @@ fn random_in_unit_sphere() -> Vector {
 // TODO Create convenience constructor funcitions that take Into<Vector> so we can use tuples and stuff like that
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Material {
-    Dielectric(Real),    // Cristal
-    Metal(Vector, Real), // Metal/Mirror
-    Diffuse(Vector),     // Lambertian, rough surface
+    Dielectric(Ior),                // Cristal
+    Metal(Vector, Real),            // Metal/Mirror
+    Diffuse(Vector),                // Lambertian, rough surface
+    Isotropic(Vector),              // Scatters the same in every direction, for media
+    HenyeyGreenstein(Vector, Real), // Forward (g > 0) or backward (g < 0) scattering, for media
+    Emissive(Vector),               // Area light, glows the same on both sides
+}
+
+fn henyey_greenstein(g: Real, cos_theta: Real) -> Real {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+// Cosine of the angle between the incoming and the scattered direction
+fn sample_henyey_greenstein(g: Real, u: Real) -> Real {
+    if g.abs() < 1e-3 {
+        return 1.0 - 2.0 * u;
+    }
+
+    let square = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+    ((1.0 + g * g - square * square) / (2.0 * g)).clamp(-1.0, 1.0)
 }
 
 fn reflectance(cos: Real, ref_idx: Real) -> Real {
@@ -52,39 +100,129 @@ fn reflectance(cos: Real, ref_idx: Real) -> Real {
     r0 + (1.0 - r0) * (1.0 - cos).powi(5)
 }
 
+// The directions light can take when it reaches a dielectric and the fraction of it that gets
+// reflected. There is no refracted direction past the critical angle
+pub fn dielectric_split(ray: &Ray, hit: &HitInfo, ref_idx: Real) -> (Vector, Option<Vector>, Real) {
+    let refraction_ratio = if hit.front_face {
+        ref_idx.recip()
+    } else {
+        ref_idx
+    };
+
+    let cos_theta = Real::min(hit.normal.dot(-ray.direction), 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let reflected = reflect(ray.direction, hit.normal);
+    if refraction_ratio * sin_theta > 1.0 {
+        return (reflected, None, 1.0);
+    }
+
+    (
+        reflected,
+        Some(refract(ray.direction, hit.normal, refraction_ratio)),
+        reflectance(cos_theta, refraction_ratio),
+    )
+}
+
 fn near_zero(v: Vector) -> bool {
     v.x.abs() < Real::EPSILON && v.y.abs() < Real::EPSILON && v.z.abs() < Real::EPSILON
 }
 
 impl Material {
-    pub fn scatter(self, ray: &Ray, hit: &HitInfo) -> Option<(Ray, Vector)> {
+    // Color of the light it lets through, used to approximate the light bouncing around
+    pub fn albedo(&self) -> Vector {
+        match *self {
+            Material::Diffuse(albedo)
+            | Material::Metal(albedo, _)
+            | Material::Isotropic(albedo)
+            | Material::HenyeyGreenstein(albedo, _) => albedo,
+            Material::Dielectric(_) => Vector::ONE,
+            Material::Emissive(_) => Vector::ZERO,
+        }
+    }
+
+    // Mirrors and glass send the light towards exact directions
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Material::Dielectric(_) | Material::Metal(..))
+    }
+
+    // Media scatter the light inside them, with no surface to slant it
+    pub fn is_medium(&self) -> bool {
+        matches!(
+            self,
+            Material::Isotropic(_) | Material::HenyeyGreenstein(..)
+        )
+    }
+
+    // Glass that bends each wavelength its own way
+    pub fn is_dispersive(&self) -> bool {
+        matches!(self, Material::Dielectric(ior) if !matches!(ior, Ior::Constant(_)))
+    }
+
+    // The material as light of a single wavelength sees it
+    pub fn at_wavelength(self, wavelength: Real) -> Material {
         match self {
-            Material::Dielectric(ref_idx) => {
-                // TODO This seems to be broken again. At some point I got it working, let´s look at the git history
-                let refraction_ratio = if hit.front_face {
-                    ref_idx.recip()
-                } else {
-                    ref_idx
-                };
+            Material::Dielectric(ior) => Material::Dielectric(Ior::Constant(ior.at(wavelength))),
+            _ => self,
+        }
+    }
 
-                let cos_theta = Real::min(hit.normal.dot(-ray.direction), 1.0);
-                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    pub fn emitted(&self) -> Vector {
+        match self {
+            Material::Emissive(color) => *color,
+            _ => Vector::ZERO,
+        }
+    }
 
-                let cannot_refact = refraction_ratio * sin_theta > 1.0;
+    // Fraction of the light arriving along `direction` that leaves back along the ray, cosine
+    // included. None for materials that only scatter towards exact directions, lights can't be
+    // sampled for those
+    pub fn eval(&self, ray: &Ray, hit: &HitInfo, direction: Vector) -> Option<Vector> {
+        match *self {
+            Material::Diffuse(albedo) => Some(albedo / PI * hit.normal.dot(direction).max(0.0)),
+            Material::Isotropic(albedo) => Some(albedo / (4.0 * PI)),
+            Material::HenyeyGreenstein(albedo, g) => {
+                Some(albedo * henyey_greenstein(g, ray.direction.dot(direction)))
+            }
+            Material::Dielectric(_) | Material::Metal(..) | Material::Emissive(_) => None,
+        }
+    }
 
-                let direction = if cannot_refact
-                    || reflectance(cos_theta, refraction_ratio)
-                        > nanorand::tls_rng().generate::<Real>()
-                {
-                    reflect(ray.direction, hit.normal)
-                } else {
-                    refract(ray.direction, hit.normal, refraction_ratio)
+    // Density of `scatter` picking the direction, over solid angle. 0 for the materials that
+    // scatter towards exact directions
+    pub fn pdf(&self, ray: &Ray, hit: &HitInfo, direction: Vector) -> Real {
+        match *self {
+            Material::Diffuse(_) => hit.normal.dot(direction.normalize()).max(0.0) / PI,
+            Material::Isotropic(_) => 1.0 / (4.0 * PI),
+            Material::HenyeyGreenstein(_, g) => {
+                henyey_greenstein(g, ray.direction.dot(direction.normalize()))
+            }
+            Material::Dielectric(_) | Material::Metal(..) | Material::Emissive(_) => 0.0,
+        }
+    }
+
+    pub fn scatter(
+        self,
+        ray: &Ray,
+        hit: &HitInfo,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, Vector)> {
+        match self {
+            Material::Dielectric(ior) => {
+                // TODO This seems to be broken again. At some point I got it working, let´s look at the git history
+                let (reflected, refracted, reflectance) =
+                    dielectric_split(ray, hit, ior.at(D_LINE));
+
+                let direction = match refracted {
+                    Some(refracted) if reflectance <= sampler.get_1d() => refracted,
+                    _ => reflected,
                 };
                 Some((Ray::new(hit.point, direction), Vector::ONE))
             }
             Material::Metal(albedo, fuzz) => {
                 let reflected = reflect(ray.direction, hit.normal);
-                let scatered = Ray::new(hit.point, reflected + random_in_unit_sphere() * fuzz);
+                let scatered =
+                    Ray::new(hit.point, reflected + random_in_unit_sphere(sampler) * fuzz);
                 if scatered.direction.dot(hit.normal) > 0.0 {
                     Some((scatered, albedo))
                 } else {
@@ -92,7 +230,7 @@ impl Material {
                 }
             }
             Material::Diffuse(albedo) => {
-                let scatter_direction = hit.normal + random_in_unit_sphere().normalize();
+                let scatter_direction = hit.normal + random_unit_vector(sampler);
                 let direction = if near_zero(scatter_direction) {
                     hit.normal
                 } else {
@@ -100,6 +238,51 @@ impl Material {
                 };
                 Some((Ray::new(hit.point, direction), albedo))
             }
+            Material::Isotropic(albedo) => {
+                Some((Ray::new(hit.point, random_unit_vector(sampler)), albedo))
+            }
+            Material::HenyeyGreenstein(albedo, g) => {
+                let (u, v) = sampler.get_2d();
+                let cos_theta = sample_henyey_greenstein(g, u);
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * v;
+
+                let (tangent, bitangent) = ray.direction.any_orthonormal_pair();
+                let direction = ray.direction * cos_theta
+                    + (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta;
+                Some((Ray::new(hit.point, direction), albedo))
+            }
+            Material::Emissive(_) => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_of_refraction_from_scene_files() {
+        let constant: Material = serde_json::from_str(r#"{"Dielectric": 1.5}"#).unwrap();
+        assert!(matches!(constant, Material::Dielectric(Ior::Constant(ior)) if ior == 1.5));
+        assert!(!constant.is_dispersive());
+
+        // BK7, the usual crown glass, is 1.5168 at the d line
+        let bk7: Material = serde_json::from_str(
+            r#"{"Dielectric": {"b": [1.03961212, 0.231792344, 1.01046945],
+                               "c": [0.00600069867, 0.0200179144, 103.560653]}}"#,
+        )
+        .unwrap();
+        let ior = match bk7 {
+            Material::Dielectric(ior @ Ior::Sellmeier { .. }) => ior,
+            _ => panic!("{bk7:?}"),
+        };
+        assert!((ior.at(D_LINE) - 1.5168).abs() < 1e-4);
+        // Blue bends more than red
+        assert!(ior.at(450.0) > ior.at(650.0));
+        assert!(matches!(
+            bk7.at_wavelength(500.0),
+            Material::Dielectric(Ior::Constant(_))
+        ));
+    }
+}
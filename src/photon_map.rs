@@ -0,0 +1,384 @@
+use std::{cmp::Ordering, collections::BinaryHeap, f64::consts::PI, ops::Range};
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    aabb::Aabb,
+    hit::{Hit, HitInfo},
+    integrators::{Integrator, PathHook, PathTracer, Radiance},
+    materials::Material,
+    ray::Ray,
+    sampler::{Sampler, SamplerKind},
+    spectrum::Wavelengths,
+    world::World,
+    Real, Vector,
+};
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct PhotonSettings {
+    pub photons: usize, // Shot from the lights, most never make it to the map
+    pub nearest: usize, // Photons gathered for each estimate
+    // Farthest a photon can be from the point to count, a hundredth of the size of the scene
+    // when missing
+    pub radius: Option<Real>,
+}
+
+impl Default for PhotonSettings {
+    fn default() -> Self {
+        Self {
+            photons: 200_000,
+            nearest: 64,
+            radius: None,
+        }
+    }
+}
+
+// Light that landed on a rough surface
+struct Photon {
+    point: Vector,
+    from: Vector, // Direction it came from
+    power: Vector,
+}
+
+// Photons ordered so that the middle one of every slice splits the rest of it in half along an
+// axis, a balanced kd-tree with no pointers
+struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>, // Axis each photon splits its slice along
+}
+
+// Photon found around a point, the farthest one first out of the heap
+struct Neighbor {
+    distance_squared: Real,
+    index: usize,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_squared
+            .total_cmp(&other.distance_squared)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let min = photons
+        .iter()
+        .fold(Vector::splat(Real::INFINITY), |a, b| a.min(b.point));
+    let max = photons
+        .iter()
+        .fold(Vector::splat(Real::NEG_INFINITY), |a, b| a.max(b.point));
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| a.point[axis].total_cmp(&b.point[axis]));
+    axes[middle] = axis;
+
+    let (first, second) = photons.split_at_mut(middle);
+    let (first_axes, second_axes) = axes.split_at_mut(middle);
+    build(first, first_axes);
+    build(&mut second[1..], &mut second_axes[1..]);
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    // Up to `count` photons closest to the point, none farther than the radius
+    fn nearest(&self, point: Vector, count: usize, radius: Real) -> Vec<Neighbor> {
+        let mut heap = BinaryHeap::with_capacity(count + 1);
+        if count > 0 {
+            let range = 0..self.photons.len();
+            self.search(range, point, count, &mut heap, radius * radius);
+        }
+        heap.into_vec()
+    }
+
+    fn search(
+        &self,
+        range: Range<usize>,
+        point: Vector,
+        count: usize,
+        heap: &mut BinaryHeap<Neighbor>,
+        mut max_distance_squared: Real,
+    ) -> Real {
+        if range.is_empty() {
+            return max_distance_squared;
+        }
+        let middle = (range.start + range.end) / 2;
+        let photon = &self.photons[middle];
+        let offset = point[self.axes[middle]] - photon.point[self.axes[middle]];
+        let (near, far) = if offset < 0.0 {
+            (range.start..middle, middle + 1..range.end)
+        } else {
+            (middle + 1..range.end, range.start..middle)
+        };
+
+        max_distance_squared = self.search(near, point, count, heap, max_distance_squared);
+
+        let distance_squared = photon.point.distance_squared(point);
+        if distance_squared < max_distance_squared {
+            heap.push(Neighbor {
+                distance_squared,
+                index: middle,
+            });
+            if heap.len() > count {
+                heap.pop();
+            }
+            // Once there are enough, only closer ones are worth looking for
+            if heap.len() == count {
+                max_distance_squared = heap
+                    .peek()
+                    .map_or(max_distance_squared, |farthest| farthest.distance_squared);
+            }
+        }
+
+        // The other side is only worth it if the splitting plane is close enough
+        if offset * offset < max_distance_squared {
+            max_distance_squared = self.search(far, point, count, heap, max_distance_squared);
+        }
+        max_distance_squared
+    }
+}
+
+// Follows a photon from one of the lights. Only the ones that go through mirrors and glass before
+// landing on a rough surface are kept, the light reaching it any other way is found by the paths
+// from the camera
+fn trace_photon(world: &World, sampler: &mut dyn Sampler, depth: usize) -> Option<Photon> {
+    let origin = world.lights.sample_origin(&world.scene, sampler)?;
+    let (direction, pdf_direction) =
+        world
+            .lights
+            .sample_direction(&world.scene, &origin, sampler.get_2d())?;
+    if pdf_direction == 0.0 {
+        return None;
+    }
+    let emitted = world
+        .lights
+        .emitted(&world.scene, origin.light, origin.normal, direction);
+    let cos = origin
+        .normal
+        .map_or(1.0, |normal| normal.dot(direction).abs());
+
+    let mut power = emitted * cos / (origin.pdf * pdf_direction);
+    let mut ray = Ray::new(origin.point, direction);
+    for bounce in 0..depth {
+        let info = world.sample_hit(&ray, sampler)?.get_hit_info(&ray);
+        let material = &world.scene.materials[info.material];
+        if !material.is_specular() {
+            if bounce == 0 || material.is_medium() {
+                return None;
+            }
+            return Some(Photon {
+                point: info.point,
+                from: -ray.direction,
+                power,
+            });
+        }
+
+        let (scattered, attenuation) = material.scatter(&ray, &info, sampler)?;
+        power *= attenuation;
+        ray = scattered;
+    }
+    None
+}
+
+// Path tracing for most of the light, and density estimation on a map of photons shot from the
+// lights for the caustics (Jensen, 1996). The paths from the camera can't sample the lights
+// through mirrors and glass, and rarely find them by chance
+pub struct PhotonMapper {
+    settings: PhotonSettings,
+    ttl: usize,
+    map: PhotonMap,
+    radius: Real,
+    path_tracer: PathTracer,
+}
+
+impl PhotonMapper {
+    pub fn new(settings: PhotonSettings, ttl: usize) -> Self {
+        Self {
+            settings,
+            ttl,
+            map: PhotonMap::new(Vec::new()),
+            radius: settings.radius.unwrap_or(0.0),
+            path_tracer: PathTracer::new(ttl, false),
+        }
+    }
+
+    // Light reaching the point through mirrors and glass, from the photons around it. Each one
+    // counts as if it had landed right on the point
+    fn caustics(&self, ray: &Ray, hit: &HitInfo, material: &Material) -> Vector {
+        let neighbors = self
+            .map
+            .nearest(hit.point, self.settings.nearest, self.radius);
+        // With enough photons around, the disk they cover. Otherwise the whole radius
+        let radius_squared = match neighbors.iter().max() {
+            Some(farthest) if neighbors.len() == self.settings.nearest => farthest.distance_squared,
+            _ => self.radius * self.radius,
+        };
+        if radius_squared == 0.0 {
+            return Vector::ZERO;
+        }
+
+        let mut reflected = Vector::ZERO;
+        for neighbor in &neighbors {
+            let photon = &self.map.photons[neighbor.index];
+            let cos = hit.normal.dot(photon.from);
+            // Photons on the other side of the surface
+            if cos <= 0.0 {
+                continue;
+            }
+            if let Some(eval) = material.eval(ray, hit, photon.from) {
+                reflected += eval / cos * photon.power;
+            }
+        }
+        reflected / (PI * radius_squared)
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn prepare(&mut self, world: &World, seed: u64) {
+        self.radius = self.settings.radius.unwrap_or_else(|| {
+            Aabb::from_shapes(&world.scene.shapes)
+                .map_or(1.0, |aabb| (aabb.max - aabb.min).length())
+                / 100.0
+        });
+
+        // Every photon gets its own stream so the map doesn't depend on the threads
+        let count = self.settings.photons;
+        let photons = (0..count)
+            .into_par_iter()
+            .filter_map(|index| {
+                let mut sampler = SamplerKind::Independent.create(seed, 1);
+                sampler.start_sample((index, 0), 0);
+                let mut photon = trace_photon(world, sampler.as_mut(), self.ttl)?;
+                photon.power /= count as Real;
+                Some(photon)
+            })
+            .collect::<Vec<_>>();
+        self.map = PhotonMap::new(photons);
+    }
+
+    // The path tracer, leaving out the lights found through mirrors and glass after a rough
+    // surface. The photons bring that light instead
+    fn radiance(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> Radiance {
+        let mut caustics = Caustics {
+            mapper: self,
+            after_rough: false,
+            through_specular: false,
+        };
+        self.path_tracer
+            .trace(ray, world, sampler, Wavelengths::RGB, &mut caustics)
+    }
+}
+
+// What a path from the camera has been through, to tell which lights the photons bring
+struct Caustics<'a> {
+    mapper: &'a PhotonMapper,
+    // Whether the last surface that wasn't a mirror or glass was rough, and mirrors or glass
+    // came after it
+    after_rough: bool,
+    through_specular: bool,
+}
+
+impl PathHook for Caustics<'_> {
+    fn keeps_emission(&self, world: &World, hit: &Hit) -> bool {
+        let in_map = world.lights.of_shape(hit.shape).is_some();
+        !(self.after_rough && self.through_specular && in_map)
+    }
+
+    fn visit(&mut self, ray: &Ray, hit: &HitInfo, material: &Material) -> Vector {
+        if material.is_specular() {
+            self.through_specular = true;
+            return Vector::ZERO;
+        }
+
+        self.after_rough = !material.is_medium();
+        self.through_specular = false;
+        // Photons went through at least one mirror or glass before landing
+        if material.is_medium() {
+            Vector::ZERO
+        } else {
+            self.mapper.caustics(ray, hit, material)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_nearest_photons() {
+        // Scattered around without any pattern the tree could lean on
+        let points = (0..500)
+            .map(|i| {
+                let i = i as Real;
+                Vector::new(
+                    (i * 0.618_034).fract(),
+                    (i * 0.754_878).fract(),
+                    (i * 0.569_840).fract(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let photons = points
+            .iter()
+            .map(|point| Photon {
+                point: *point,
+                from: Vector::Y,
+                power: Vector::ONE,
+            })
+            .collect();
+        let map = PhotonMap::new(photons);
+
+        let point = Vector::new(0.4, 0.5, 0.6);
+        let mut found = map
+            .nearest(point, 10, 0.3)
+            .iter()
+            .map(|neighbor| neighbor.distance_squared)
+            .collect::<Vec<_>>();
+        found.sort_by(Real::total_cmp);
+
+        let mut expected = points
+            .iter()
+            .map(|p| p.distance_squared(point))
+            .collect::<Vec<_>>();
+        expected.sort_by(Real::total_cmp);
+        assert_eq!(found, expected[..10]);
+
+        // Fewer than asked for within a small radius
+        let close = expected.iter().filter(|d| **d < 0.1 * 0.1).count();
+        assert!(close < 10);
+        assert_eq!(map.nearest(point, 10, 0.1).len(), close);
+    }
+}
@@ -0,0 +1,137 @@
+use std::f64::consts::PI;
+
+use crate::Real;
+
+// Real roots of a*x^2 + b*x + c, in ascending order
+pub fn solve_quadratic(a: Real, b: Real, c: Real) -> Vec<Real> {
+    if a.abs() < Real::EPSILON {
+        return if b.abs() < Real::EPSILON {
+            Vec::new()
+        } else {
+            vec![-c / b]
+        };
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant.is_sign_negative() {
+        return Vec::new();
+    }
+
+    // Avoids the cancellation of the textbook formula when b is much bigger than a*c
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (x0, x1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+
+    if x0 < x1 {
+        vec![x0, x1]
+    } else {
+        vec![x1, x0]
+    }
+}
+
+// Real roots of x^3 + a*x^2 + b*x + c
+fn solve_normalized_cubic(a: Real, b: Real, c: Real) -> Vec<Real> {
+    // Substitute x = t - a/3 to get t^3 + p*t + q
+    let shift = a / 3.0;
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+
+    let discriminant = (q / 2.0).powi(2) + (p / 3.0).powi(3);
+
+    if discriminant > 0.0 {
+        let sqrt_discriminant = discriminant.sqrt();
+        let t = (-q / 2.0 + sqrt_discriminant).cbrt() + (-q / 2.0 - sqrt_discriminant).cbrt();
+        vec![t - shift]
+    } else if p == 0.0 {
+        vec![-shift]
+    } else {
+        // Three real roots, use the trigonometric solution
+        let radius = 2.0 * (-p / 3.0).sqrt();
+        let angle = ((3.0 * q / (2.0 * p)) * (-3.0 / p).sqrt())
+            .clamp(-1.0, 1.0)
+            .acos()
+            / 3.0;
+        (0..3)
+            .map(|k| radius * (angle - 2.0 * PI * k as Real / 3.0).cos() - shift)
+            .collect()
+    }
+}
+
+// Real roots of a*x^4 + b*x^3 + c*x^2 + d*x + e, in ascending order
+pub fn solve_quartic(a: Real, b: Real, c: Real, d: Real, e: Real) -> Vec<Real> {
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+
+    // Substitute x = y - b/4 to get y^4 + p*y^2 + q*y + r
+    let shift = b / 4.0;
+    let p = c - 3.0 * b * b / 8.0;
+    let q = d - b * c / 2.0 + b * b * b / 8.0;
+    let r = e - b * d / 4.0 + b * b * c / 16.0 - 3.0 * b * b * b * b / 256.0;
+
+    let mut roots = if q.abs() < 1e-12 {
+        // Biquadratic, solve for y^2
+        solve_quadratic(1.0, p, r)
+            .into_iter()
+            .filter(|z| *z >= 0.0)
+            .flat_map(|z| [-z.sqrt(), z.sqrt()])
+            .collect::<Vec<_>>()
+    } else {
+        // Ferrari: any positive root of the resolvent cubic splits it into two quadratics
+        let m = solve_normalized_cubic(p, p * p / 4.0 - r, -q * q / 8.0)
+            .into_iter()
+            .fold(Real::MIN, Real::max);
+        if m <= 0.0 {
+            return Vec::new();
+        }
+
+        let s = (2.0 * m).sqrt();
+        let mut roots = solve_quadratic(1.0, -s, p / 2.0 + m + q / (2.0 * s));
+        roots.extend(solve_quadratic(1.0, s, p / 2.0 + m - q / (2.0 * s)));
+        roots
+    };
+
+    // The closed form loses a lot of precision, polish the roots with a couple of Newton steps
+    for root in roots.iter_mut() {
+        let mut x = *root - shift;
+        for _ in 0..2 {
+            let value = (((x + b) * x + c) * x + d) * x + e;
+            let derivative = ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+            if derivative.abs() > Real::EPSILON {
+                x -= value / derivative;
+            }
+        }
+        *root = x;
+    }
+
+    roots.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(found: Vec<Real>, expected: &[Real]) {
+        assert_eq!(found.len(), expected.len(), "{found:?} != {expected:?}");
+        for (found, expected) in found.iter().zip(expected) {
+            assert!((found - expected).abs() < 1e-6, "{found} != {expected}");
+        }
+    }
+
+    #[test]
+    fn quadratic() {
+        assert_roots(solve_quadratic(1.0, -3.0, 2.0), &[1.0, 2.0]);
+        assert_roots(solve_quadratic(1.0, 0.0, 1.0), &[]);
+    }
+
+    #[test]
+    fn quartic() {
+        // (x - 1)(x - 2)(x + 3)(x - 0.5)
+        assert_roots(
+            solve_quartic(1.0, -0.5, -7.0, 9.5, -3.0),
+            &[-3.0, 0.5, 1.0, 2.0],
+        );
+        // (x^2 - 4)(x^2 + 1)
+        assert_roots(solve_quartic(1.0, 0.0, -3.0, 0.0, -4.0), &[-2.0, 2.0]);
+        // x^4 + 1 has no real roots
+        assert_roots(solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0), &[]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{adaptive::Variance, Real};
+
+// Keeps adding one sample to every pixel until one of the limits is reached, writing the image
+// so far every now and then
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[serde(default)]
+pub struct Progressive {
+    pub time_budget: Real, // Seconds
+    pub target_samples: Option<usize>,
+    // Average over the pixels of the noise measured like in adaptive sampling
+    pub noise_threshold: Option<Real>,
+    pub write_interval: Real, // Seconds
+}
+
+impl Default for Progressive {
+    fn default() -> Self {
+        Self {
+            time_budget: 60.0,
+            target_samples: None,
+            noise_threshold: None,
+            write_interval: 10.0,
+        }
+    }
+}
+
+impl Progressive {
+    pub fn samples(&self) -> usize {
+        self.target_samples.unwrap_or(usize::MAX)
+    }
+
+    pub fn out_of_time(&self, start: Instant) -> bool {
+        start.elapsed() >= Duration::from_secs_f64(self.time_budget)
+    }
+
+    pub fn write_due(&self, last_write: Instant) -> bool {
+        last_write.elapsed() >= Duration::from_secs_f64(self.write_interval)
+    }
+
+    pub fn clean_enough<'a>(&self, variances: impl ExactSizeIterator<Item = &'a Variance>) -> bool {
+        let threshold = match self.noise_threshold {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let count = variances.len() as Real;
+        variances.map(Variance::error).sum::<Real>() / count <= threshold
+    }
+}
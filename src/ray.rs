@@ -1,5 +1,3 @@
-use crate::bvh::Bvh;
-use crate::scene::Scene;
 use crate::*;
 
 #[derive(Debug, Copy, Clone)]
@@ -19,27 +17,4 @@ impl Ray {
     pub fn point(&self, t: Real) -> Vector {
         self.origin + self.direction * t
     }
-
-    pub fn bounce(&self, bvh: &Bvh, scene: &Scene, ambient_color: &Vector, ttl: usize) -> Vector {
-        if ttl == 0 {
-            return *ambient_color;
-        }
-
-        match bvh.hit(self, self.direction.recip(), &scene.shapes) {
-            Some(h) => {
-                let res = scene.materials[h.material].scatter(self, &h.get_hit_info(self));
-
-                match res {
-                    Some((scattered, attenuation)) => {
-                        attenuation * scattered.bounce(bvh, scene, ambient_color, ttl - 1)
-                    }
-                    None => Vector::ZERO,
-                }
-            }
-            None => {
-                let t = 0.5 * (self.direction.y + 1.0);
-                Vector::splat(1.0) * (1.0 - t) + *ambient_color * t
-            }
-        }
-    }
 }
@@ -1,35 +1,150 @@
+use std::time::Instant;
+
 use nanorand::*;
 use rayon::{prelude::ParallelIterator, slice::ParallelSliceMut};
 
-use crate::{bvh::Bvh, camera::Camera, config::Config, scene::Scene, Real, Vector};
+use crate::{
+    adaptive::Variance,
+    aov::Aovs,
+    camera::Camera,
+    config::Config,
+    film::{Film, FilmSample},
+    scene::Scene,
+    world::World,
+    Real, Vector,
+};
 
 fn indeces_2d(width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
     (0..height).flat_map(move |row| (0..width).map(move |col| (col, row)))
 }
 
-pub fn raytrace(config: &Config) -> impl IntoIterator<Item = Vector> {
-    let scene = Scene::read_scene(&config.scene);
-    let camera = Camera::new(&scene, config.aspect_ratio);
-    let bvh = Bvh::new(&scene.shapes);
+// Light averaged over the samples of each pixel, row by row from the bottom
+pub struct Render {
+    pub pixels: Vec<Vector>,
+    pub aovs: Option<Vec<Aovs>>,
+    pub samples: Vec<usize>, // Taken on each pixel
+    pub seed: u64,
+}
+
+#[derive(Default)]
+struct Pixel {
+    aovs: Aovs,
+    samples: usize,
+    variance: Variance,
+    index: (usize, usize),
+    // Taken in the current pass, for the film
+    sample: Option<FilmSample>,
+}
+
+fn average(pixels: &[Pixel], film: &Film, aovs_enabled: bool, seed: u64) -> Render {
+    let samples = pixels.iter().map(|pixel| pixel.samples).collect();
+    let aovs = pixels
+        .iter()
+        .map(|pixel| pixel.aovs.average(pixel.samples))
+        .collect();
+
+    Render {
+        pixels: film.pixels(),
+        aovs: aovs_enabled.then_some(aovs),
+        samples,
+        seed,
+    }
+}
+
+pub fn raytrace(config: &Config, preview: impl FnMut(&Render)) -> Render {
+    render(Scene::read_scene(&config.scene), config, preview)
+}
+
+// Renders in passes of one sample on every pixel that still needs it. In progressive mode
+// `preview` gets the image so far every `write_interval`
+pub fn render(scene: Scene, config: &Config, mut preview: impl FnMut(&Render)) -> Render {
+    let world = World::new(scene, config);
+    let camera = Camera::new(&world.scene, config.aspect_ratio);
+    // The denoiser is guided by the passes too
+    let aovs_enabled = config.aovs || config.denoiser.is_some();
+
+    let base_samples = config
+        .progressive
+        .map_or(config.samples, |progressive| progressive.samples());
+    let wants_more = |pixel: &Pixel| match config.adaptive {
+        _ if pixel.samples < base_samples => true,
+        Some(adaptive) => adaptive.wants_more(pixel.samples, &pixel.variance),
+        None => false,
+    };
 
     let mut pixels = indeces_2d(config.width, config.height)
-        .map(|index| (Vector::ZERO, index))
+        .map(|index| Pixel {
+            index,
+            ..Default::default()
+        })
         .collect::<Vec<_>>();
+    let mut film = Film::new(config.width, config.height, config.filter);
+
+    let seed = config
+        .seed
+        .unwrap_or_else(|| nanorand::tls_rng().generate::<u64>());
+    let mut integrator = config.integrator.create(config);
+    integrator.prepare(&world, seed);
+
+    let start = Instant::now();
+    let mut last_write = start;
+
+    loop {
+        let sampled = pixels
+            .par_chunks_mut(config.width * config.chunk_size)
+            .map(|chunk| {
+                let mut sampler = config.sampler.create(seed, config.samples);
+                let mut sampled = 0;
+                for pixel in chunk.iter_mut() {
+                    pixel.sample = None;
+                    if !wants_more(pixel) {
+                        continue;
+                    }
+
+                    let (x, y) = pixel.index;
+                    sampler.start_sample(pixel.index, pixel.samples);
+                    let (u, v) = sampler.get_2d();
+                    let film_x = x as Real + u;
+                    let film_y = y as Real + v;
+                    let ray = camera.get_pixel(
+                        film_x / config.width as Real,
+                        film_y / config.height as Real,
+                    );
 
-    pixels
-        .par_chunks_mut(config.width * config.chunk_size)
-        .for_each(|chunk| {
-            let mut rng = nanorand::tls_rng();
-            for (pixel, (x, y)) in chunk.iter_mut() {
-                for _ in 0..config.samples {
-                    let x_offset = (*x as Real + rng.generate::<Real>()) / config.width as Real;
-                    let y_offset = (*y as Real + rng.generate::<Real>()) / config.height as Real;
-                    let ray = camera.get_pixel(x_offset, y_offset);
-
-                    *pixel += ray.bounce(&bvh, &scene, &config.ambient_color, config.ttl);
+                    let radiance = integrator.radiance(&ray, &world, sampler.as_mut());
+                    pixel.sample = Some(FilmSample {
+                        x: film_x,
+                        y: film_y,
+                        color: radiance.total(),
+                    });
+                    pixel.samples += 1;
+                    pixel.variance.add(radiance.total());
+
+                    if aovs_enabled {
+                        pixel.aovs.add(&Aovs::sample(&ray, &world, &radiance));
+                    }
+                    sampled += 1;
                 }
+                sampled
+            })
+            .sum::<usize>();
+
+        if sampled == 0 {
+            break;
+        }
+        film.add_pass(&pixels.iter().map(|pixel| pixel.sample).collect::<Vec<_>>());
+
+        if let Some(progressive) = config.progressive {
+            let variances = pixels.iter().map(|pixel| &pixel.variance);
+            if progressive.out_of_time(start) || progressive.clean_enough(variances) {
+                break;
+            }
+            if progressive.write_due(last_write) {
+                preview(&average(&pixels, &film, aovs_enabled, seed));
+                last_write = Instant::now();
             }
-        });
+        }
+    }
 
-    pixels.into_iter().map(|(pixel, _)| pixel)
+    average(&pixels, &film, aovs_enabled, seed)
 }
@@ -0,0 +1,376 @@
+// Renders small scenes with a fixed seed and compares them against the images checked in under
+// `tests/references`. Run with `UPDATE_REFERENCES=1` to write the references again after a change
+// that is meant to alter the renders
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{Rgb, RgbImage};
+
+    use crate::{
+        config::Config,
+        integrators::IntegratorKind,
+        lights::Light,
+        materials::{Ior, Material},
+        photon_map::PhotonSettings,
+        raytrace::render,
+        sampler::SamplerKind,
+        scene::Scene,
+        scene_gerenators::tests::{cornell_box, dielectric, diffuse, emissive, metal},
+        sdf::Sdf,
+        shapes::{CsgOperation, ShapeKind},
+        to_image, Real, Vector,
+    };
+
+    // Root mean square error allowed, with the channels going from 0 to 1. Renders are
+    // deterministic, this only leaves room for floating point differences between platforms
+    const TOLERANCE: Real = 0.01;
+    const REFERENCES: &str = "./tests/references";
+    const FAILURES: &str = "./target/regression";
+
+    fn config(integrator: IntegratorKind) -> Config {
+        Config {
+            width: 64,
+            height: 48,
+            aspect_ratio: 64.0 / 48.0,
+            samples: 8,
+            ttl: 16,
+            chunk_size: 4,
+            integrator,
+            sampler: SamplerKind::Sobol,
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn rmse(a: &RgbImage, b: &RgbImage) -> Real {
+        let squares = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(a, b)| (*a as Real - *b as Real) / 255.0)
+            .map(|difference| difference * difference)
+            .sum::<Real>();
+        (squares / a.as_raw().len() as Real).sqrt()
+    }
+
+    // Differences scaled up 4 times so small ones still show
+    fn diff_image(a: &RgbImage, b: &RgbImage) -> RgbImage {
+        RgbImage::from_fn(a.width(), a.height(), |x, y| {
+            let (a, b) = (a.get_pixel(x, y), b.get_pixel(x, y));
+            Rgb([0, 1, 2].map(|c| (a[c].abs_diff(b[c]) as u32 * 4).min(255) as u8))
+        })
+    }
+
+    fn check(name: &str, scene: Scene, config: Config) {
+        let image = to_image(render(scene, &config, |_| ()).pixels, &config);
+        let reference_path = format!("{REFERENCES}/{name}.png");
+
+        if std::env::var_os("UPDATE_REFERENCES").is_some() {
+            fs::create_dir_all(REFERENCES).unwrap();
+            image.save(&reference_path).unwrap();
+            return;
+        }
+
+        let reference = image::open(&reference_path)
+            .unwrap_or_else(|e| panic!("No reference for {name} at {reference_path}: {e}"))
+            .to_rgb8();
+        assert_eq!(
+            reference.dimensions(),
+            image.dimensions(),
+            "{name} changed size"
+        );
+
+        let error = rmse(&image, &reference);
+        if error > TOLERANCE {
+            fs::create_dir_all(FAILURES).unwrap();
+            let actual = format!("{FAILURES}/{name}_actual.png");
+            let diff = format!("{FAILURES}/{name}_diff.png");
+            image.save(&actual).unwrap();
+            diff_image(&image, &reference).save(&diff).unwrap();
+            panic!(
+                "{name} is off by {error:.4} RMSE (tolerance {TOLERANCE}), see {actual} and {diff}"
+            );
+        }
+    }
+
+    fn sky_scene(look_from: impl Into<Vector>) -> Scene {
+        let mut scene = Scene::new(look_from.into(), Vector::new(0.0, 0.5, 0.0), 40.0);
+        let ground = scene.add_material(diffuse((0.5, 0.5, 0.5)));
+        scene
+            .shapes
+            .push(ShapeKind::Plane(Vector::ZERO, Vector::Y).with_mat(ground));
+        scene
+    }
+
+    fn materials() -> Scene {
+        let mut scene = sky_scene((0.0, 1.5, 6.0));
+        let glass = scene.add_material(dielectric(1.5));
+        let gold = scene.add_material(metal((0.8, 0.6, 0.2), 0.1));
+        let red = scene.add_material(diffuse((0.7, 0.1, 0.1)));
+
+        for (x, material) in [(-1.2, glass), (0.0, gold), (1.2, red)] {
+            scene
+                .shapes
+                .push(ShapeKind::Sphere(Vector::new(x, 0.5, 0.0), 0.5).with_mat(material));
+        }
+        scene
+    }
+
+    fn shapes() -> Scene {
+        let mut scene = sky_scene((0.0, 2.0, 6.0));
+        let blue = scene.add_material(diffuse((0.1, 0.2, 0.7)));
+        let green = scene.add_material(diffuse((0.1, 0.6, 0.2)));
+        let light = scene.add_material(emissive((4.0, 4.0, 4.0)));
+
+        let shapes = [
+            ShapeKind::Csg(
+                CsgOperation::Difference,
+                Box::new(ShapeKind::Box(
+                    Vector::new(-2.0, 0.0, -0.5),
+                    Vector::new(-1.0, 1.0, 0.5),
+                )),
+                Box::new(ShapeKind::Sphere(Vector::new(-1.5, 1.0, 0.5), 0.6)),
+            )
+            .with_mat(blue),
+            ShapeKind::Torus(Vector::new(0.0, 0.3, 0.0), Vector::Y, 0.5, 0.2).with_mat(green),
+            ShapeKind::Cylinder(Vector::new(1.5, 0.0, 0.0), Vector::new(1.5, 1.0, 0.0), 0.4)
+                .with_mat(blue),
+            ShapeKind::Sdf(
+                Sdf::Translate(
+                    Vector::new(0.0, 1.5, -1.0),
+                    Box::new(Sdf::RoundBox(Vector::splat(0.4), 0.1)),
+                ),
+                Vector::new(-0.5, 1.0, -1.5),
+                Vector::new(0.5, 2.0, -0.5),
+            )
+            .with_mat(green),
+            ShapeKind::Quad(
+                Vector::new(-1.0, 3.0, -1.0),
+                Vector::new(2.0, 0.0, 0.0),
+                Vector::new(0.0, 0.0, 2.0),
+            )
+            .with_mat(light),
+        ];
+        scene.shapes.extend(shapes);
+        scene
+    }
+
+    fn medium() -> Scene {
+        let mut scene = sky_scene((0.0, 1.5, 6.0));
+        let fog = scene.add_material(Material::Isotropic(Vector::new(0.8, 0.8, 0.9)));
+        scene.shapes.push(
+            ShapeKind::Medium(
+                Box::new(ShapeKind::Sphere(Vector::new(0.0, 0.8, 0.0), 0.8)),
+                2.0,
+            )
+            .with_mat(fog),
+        );
+        scene
+    }
+
+    fn delta_lights() -> Scene {
+        let mut scene = sky_scene((0.0, 3.0, 6.0));
+        let white = scene.add_material(diffuse((0.8, 0.8, 0.8)));
+        for x in [-1.5, 0.0, 1.5] {
+            scene
+                .shapes
+                .push(ShapeKind::Sphere(Vector::new(x, 0.5, 0.0), 0.5).with_mat(white));
+        }
+        scene.lights = vec![
+            Light::Point(Vector::new(-1.5, 2.0, 1.0), Vector::new(4.0, 1.0, 1.0)),
+            Light::Spot(
+                Vector::new(1.5, 3.0, 0.0),
+                Vector::NEG_Y,
+                Vector::new(1.0, 1.0, 8.0),
+                15.0,
+                25.0,
+            ),
+            Light::Directional(Vector::new(1.0, 1.0, 1.0), Vector::new(0.5, 0.8, 0.5)),
+        ];
+        scene
+    }
+
+    // An area light, a point light and a spot light over diffuse balls, nothing the two
+    // integrators handle differently
+    fn mixed_lights() -> Scene {
+        let mut scene = sky_scene((0.0, 3.0, 6.0));
+        let white = scene.add_material(diffuse((0.8, 0.8, 0.8)));
+        let light = scene.add_material(emissive((8.0, 6.0, 4.0)));
+        scene.shapes.extend([
+            ShapeKind::Sphere(Vector::new(-1.0, 0.5, 0.0), 0.5).with_mat(white),
+            ShapeKind::Sphere(Vector::new(1.0, 0.5, 0.0), 0.5).with_mat(white),
+            ShapeKind::Sphere(Vector::new(0.0, 2.0, -1.0), 0.3).with_mat(light),
+        ]);
+        scene.lights = vec![
+            Light::Point(Vector::new(-1.5, 2.0, 1.0), Vector::new(4.0, 4.0, 6.0)),
+            Light::Spot(
+                Vector::new(1.0, 3.0, 0.5),
+                Vector::NEG_Y,
+                Vector::new(6.0, 4.0, 4.0),
+                15.0,
+                25.0,
+            ),
+        ];
+        scene
+    }
+
+    // A light inside a glass ball, rays sent to it from the scene stop at the glass
+    fn lamp() -> Scene {
+        let mut scene = sky_scene((0.0, 2.0, 6.0));
+        let glass = scene.add_material(dielectric(1.5));
+        let light = scene.add_material(emissive((20.0, 16.0, 12.0)));
+        let white = scene.add_material(diffuse((0.8, 0.8, 0.8)));
+        scene.shapes.extend([
+            ShapeKind::Sphere(Vector::new(0.0, 1.2, 0.0), 0.15).with_mat(light),
+            ShapeKind::Sphere(Vector::new(0.0, 1.2, 0.0), 0.4).with_mat(glass),
+            ShapeKind::Sphere(Vector::new(-1.3, 0.5, 0.0), 0.5).with_mat(white),
+            ShapeKind::Sphere(Vector::new(1.3, 0.5, 0.0), 0.5).with_mat(white),
+        ]);
+        scene
+    }
+
+    // A glass ball focusing a light onto the floor
+    fn caustic() -> Scene {
+        let mut scene = sky_scene((0.0, 3.0, 6.0));
+        let glass = scene.add_material(dielectric(1.5));
+        let light = scene.add_material(emissive((40.0, 36.0, 30.0)));
+        scene.shapes.extend([
+            ShapeKind::Sphere(Vector::new(0.0, 0.6, 0.0), 0.6).with_mat(glass),
+            ShapeKind::Sphere(Vector::new(-1.5, 3.0, -1.0), 0.3).with_mat(light),
+        ]);
+        scene
+    }
+
+    // A diamond in front of a bar of light, which it splits into colors
+    fn dispersion() -> Scene {
+        let mut scene = sky_scene((0.0, 1.0, 6.0));
+        let diamond = scene.add_material(Material::Dielectric(Ior::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0],
+        }));
+        let light = scene.add_material(emissive((12.0, 12.0, 12.0)));
+        scene.shapes.extend([
+            ShapeKind::Sphere(Vector::new(0.0, 0.8, 0.0), 0.8).with_mat(diamond),
+            ShapeKind::Quad(
+                Vector::new(-0.1, 0.0, -2.5),
+                Vector::new(0.2, 0.0, 0.0),
+                Vector::new(0.0, 3.0, 0.0),
+            )
+            .with_mat(light),
+        ]);
+        scene
+    }
+
+    #[test]
+    fn cornell_box_path_traced() {
+        check(
+            "cornell_box",
+            cornell_box(),
+            config(IntegratorKind::PathTracing),
+        );
+    }
+
+    #[test]
+    fn materials_path_traced() {
+        check(
+            "materials",
+            materials(),
+            config(IntegratorKind::PathTracing),
+        );
+    }
+
+    #[test]
+    fn shapes_whitted() {
+        check("shapes", shapes(), config(IntegratorKind::Whitted));
+    }
+
+    #[test]
+    fn medium_direct_lighting() {
+        check("medium", medium(), config(IntegratorKind::DirectLighting));
+    }
+
+    #[test]
+    fn delta_lights_path_traced() {
+        // A dim sky so the lights show
+        let config = Config {
+            ambient_color: Vector::ZERO,
+            ..config(IntegratorKind::PathTracing)
+        };
+        check("delta_lights", delta_lights(), config);
+    }
+
+    #[test]
+    fn lamp_bidirectional() {
+        let config = Config {
+            ambient_color: Vector::ZERO,
+            ..config(IntegratorKind::Bidirectional)
+        };
+        check("lamp", lamp(), config);
+    }
+
+    #[test]
+    fn caustic_photon_mapping() {
+        let settings = PhotonSettings {
+            photons: 50_000,
+            ..Default::default()
+        };
+        let config = Config {
+            ambient_color: Vector::ZERO,
+            ..config(IntegratorKind::PhotonMapping(settings))
+        };
+        check("caustic", caustic(), config);
+    }
+
+    #[test]
+    fn dispersion_spectral() {
+        let config = Config {
+            ambient_color: Vector::ZERO,
+            spectral: true,
+            ..config(IntegratorKind::PathTracing)
+        };
+        check("dispersion", dispersion(), config);
+    }
+
+    // Both integrators converge to the same image, the means of the renders tell them apart
+    // when one of them loses or doubles some of the light
+    #[test]
+    fn bidirectional_matches_path_tracing() {
+        let mean = |integrator| {
+            let config = Config {
+                width: 32,
+                height: 24,
+                aspect_ratio: 32.0 / 24.0,
+                samples: 64,
+                ttl: 8,
+                ambient_color: Vector::ZERO,
+                ..config(integrator)
+            };
+            let pixels = render(mixed_lights(), &config, |_| ()).pixels;
+            pixels.iter().sum::<Vector>() / pixels.len() as Real
+        };
+
+        let path_traced = mean(IntegratorKind::PathTracing);
+        let bidirectional = mean(IntegratorKind::Bidirectional);
+        assert!(
+            (bidirectional - path_traced)
+                .abs()
+                .cmple(path_traced * 0.02)
+                .all(),
+            "Bidirectional {bidirectional} against path traced {path_traced}"
+        );
+    }
+
+    #[test]
+    fn same_seed_same_image() {
+        let chunks = config(IntegratorKind::PathTracing);
+        let rows = Config {
+            chunk_size: 1,
+            ..config(IntegratorKind::PathTracing)
+        };
+
+        let a = render(medium(), &chunks, |_| ()).pixels;
+        let b = render(medium(), &rows, |_| ()).pixels;
+        assert!(a == b, "Chunks changed the render");
+    }
+}
@@ -0,0 +1,317 @@
+use nanorand::{Rng, WyRand};
+use serde::{Deserialize, Serialize};
+
+use crate::Real;
+
+// Halton uses a prime base per dimension, past these the samples are independent
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// Source of the random numbers of a sample. Each value asked for is a dimension: the first two
+// place the ray in the pixel, the rest go to lights and materials in the order they need them.
+// Spreading the samples of a pixel evenly on every dimension lowers the noise
+pub trait Sampler {
+    // Moves on to the given sample of the pixel, back to the first dimension
+    fn start_sample(&mut self, pixel: (usize, usize), index: usize);
+    fn get_1d(&mut self) -> Real;
+    fn get_2d(&mut self) -> (Real, Real);
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
+pub enum SamplerKind {
+    #[default]
+    Independent,
+    Stratified, // Jittered strata over the `samples` of the config, independent past them
+    Halton,
+    Sobol, // Owen scrambled
+}
+
+impl SamplerKind {
+    // Samplers hold the state of the sample, every thread needs its own
+    pub fn create(self, seed: u64, samples: usize) -> Box<dyn Sampler> {
+        let stream = Stream::new(seed);
+        match self {
+            SamplerKind::Independent => Box::new(Independent(stream)),
+            SamplerKind::Stratified => Box::new(Stratified {
+                stream,
+                samples: samples.max(1) as u32,
+            }),
+            SamplerKind::Halton => Box::new(Halton(stream)),
+            SamplerKind::Sobol => Box::new(Sobol(stream)),
+        }
+    }
+}
+
+// Mixes the bits of the values into a new one (SplitMix64 finalizer)
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |state, value| {
+        let mut x = (state ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    })
+}
+
+fn to_unit(x: u32) -> Real {
+    x as Real / (1u64 << 32) as Real
+}
+
+// Where the sampler is: which pixel, sample and dimension. The random numbers come from a
+// generator seeded from all of that, so they don't depend on the thread doing the work
+struct Stream {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+    rng: WyRand,
+}
+
+impl Stream {
+    fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+            rng: WyRand::new_seed(seed),
+        }
+    }
+
+    fn start_sample(&mut self, (x, y): (usize, usize), index: usize) {
+        self.pixel = hash(&[self.seed, x as u64, y as u64]);
+        self.index = index as u32;
+        self.dimension = 0;
+        self.rng = WyRand::new_seed(hash(&[self.pixel, index as u64]));
+    }
+
+    // Seed for scrambling the current dimension of the pixel, the same for all its samples
+    fn next_dimension(&mut self) -> u64 {
+        self.dimension += 1;
+        hash(&[self.pixel, self.dimension as u64])
+    }
+
+    fn random(&mut self) -> Real {
+        self.rng.generate()
+    }
+}
+
+pub struct Independent(Stream);
+
+impl Sampler for Independent {
+    fn start_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.0.start_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> Real {
+        self.0.random()
+    }
+
+    fn get_2d(&mut self) -> (Real, Real) {
+        (self.0.random(), self.0.random())
+    }
+}
+
+// Random permutation of 0..length picked by the seed, without building it (Kensler, 2013)
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        // Values out of range go around again until they land inside
+        if i < length {
+            return i.wrapping_add(seed) % length;
+        }
+    }
+}
+
+// Splits every dimension in as many strata as samples (a square grid for 2D) and puts each
+// sample in a different one, shuffled per dimension so they don't line up
+pub struct Stratified {
+    stream: Stream,
+    samples: u32,
+}
+
+impl Sampler for Stratified {
+    fn start_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.stream.start_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> Real {
+        let seed = self.stream.next_dimension() as u32;
+        if self.stream.index >= self.samples {
+            return self.stream.random();
+        }
+
+        let stratum = permute(self.stream.index, self.samples, seed);
+        (stratum as Real + self.stream.random()) / self.samples as Real
+    }
+
+    fn get_2d(&mut self) -> (Real, Real) {
+        let seed = self.stream.next_dimension() as u32;
+        let side = (self.samples as Real).sqrt() as u32;
+        if self.stream.index >= side * side {
+            return (self.stream.random(), self.stream.random());
+        }
+
+        let stratum = permute(self.stream.index, side * side, seed);
+        (
+            ((stratum % side) as Real + self.stream.random()) / side as Real,
+            ((stratum / side) as Real + self.stream.random()) / side as Real,
+        )
+    }
+}
+
+fn radical_inverse(base: u32, mut index: u32) -> Real {
+    let mut inverse = 0.0;
+    let mut digit_weight = 1.0;
+    while index > 0 {
+        digit_weight /= base as Real;
+        inverse += (index % base) as Real * digit_weight;
+        index /= base;
+    }
+    inverse
+}
+
+// Radical inverse of the sample index in a different prime base per dimension, shifted by a
+// random offset per pixel (Cranley-Patterson rotation) so neighbours don't repeat the pattern
+pub struct Halton(Stream);
+
+impl Sampler for Halton {
+    fn start_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.0.start_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> Real {
+        let dimension = self.0.dimension as usize;
+        let offset = to_unit(self.0.next_dimension() as u32);
+        match PRIMES.get(dimension) {
+            Some(base) => (radical_inverse(*base, self.0.index) + offset).fract(),
+            None => self.0.random(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (Real, Real) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// First two dimensions of the Sobol sequence, as bits of a 32 bit fraction
+fn sobol(index: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+
+    // Direction numbers of the polynomial x + 1
+    let mut direction = 1 << 31;
+    let mut result = 0;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 == 1 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+// Owen scrambling flips each bit depending on all the bits above it (Burley, 2020)
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// Owen scrambled 2D Sobol points. Every dimension (or pair of them) gets its own scramble and
+// its own shuffle of the sample order, which keeps them from correlating with each other
+pub struct Sobol(Stream);
+
+impl Sobol {
+    fn point(&mut self, dimensions: usize) -> [Real; 2] {
+        let seed = self.0.next_dimension();
+        let index = nested_uniform_scramble(self.0.index, seed as u32);
+
+        let mut point = [0.0; 2];
+        for (dimension, value) in point.iter_mut().enumerate().take(dimensions) {
+            let scramble = hash(&[seed, dimension as u64]) as u32;
+            *value = to_unit(nested_uniform_scramble(sobol(index, dimension), scramble));
+        }
+        point
+    }
+}
+
+impl Sampler for Sobol {
+    fn start_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.0.start_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> Real {
+        self.point(1)[0]
+    }
+
+    fn get_2d(&mut self) -> (Real, Real) {
+        let [u, v] = self.point(2);
+        (u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permute_is_a_permutation() {
+        for length in [1, 5, 16, 100] {
+            let mut values = (0..length)
+                .map(|i| permute(i, length, 1234))
+                .collect::<Vec<_>>();
+            values.sort_unstable();
+            assert_eq!(values, (0..length).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn stratified_covers_every_stratum() {
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = kind.create(7, 16);
+            let mut strata = [false; 16];
+            for index in 0..16 {
+                sampler.start_sample((3, 4), index);
+                let (u, v) = sampler.get_2d();
+                strata[(u * 4.0) as usize * 4 + (v * 4.0) as usize] = true;
+            }
+            assert!(strata.iter().all(|hit| *hit), "{kind:?} left strata empty");
+        }
+    }
+}
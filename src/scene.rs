@@ -2,15 +2,22 @@ use std::fs::File;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{materials::Material, shapes::Shape, Real, Vector};
+use crate::{
+    environment::Environment, lights::Light, materials::Material, shapes::Shape, Real, Vector,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Scene {
     pub shapes: Vec<Shape>,
     pub materials: Vec<Material>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lights: Vec<Light>,
     pub look_from: Vector,
     pub look_at: Vector,
     pub fov: Real,
+    // Overrides the environment of the config, for scenes made for a given sky
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<Environment>,
 }
 
 impl Scene {
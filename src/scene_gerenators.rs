@@ -1,16 +1,16 @@
-// TODO Cornell box
-
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use std::{fs::File, io::Write};
 
     use easy_gltf::model::Vertex;
     use nanorand::{tls::TlsWyRand, Rng};
 
     use crate::{
+        environment::Environment,
         materials::*,
         scene::Scene,
         shapes::{Shape, ShapeKind},
+        sky::SkyParameters,
         Real, Vector,
     };
 
@@ -19,9 +19,11 @@ mod tests {
             Self {
                 shapes: Vec::new(),
                 materials: Vec::new(),
+                lights: Vec::new(),
                 look_from,
                 look_at,
                 fov,
+                environment: None,
             }
         }
         pub fn add_material(&mut self, material: Material) -> MaterialRef {
@@ -32,7 +34,7 @@ mod tests {
     }
 
     pub fn dielectric(a: Real) -> Material {
-        Material::Dielectric(a)
+        Material::Dielectric(Ior::Constant(a))
     }
 
     pub fn metal(v: impl Into<Vector>, a: Real) -> Material {
@@ -42,6 +44,10 @@ mod tests {
     pub fn diffuse(v: impl Into<Vector>) -> Material {
         Material::Diffuse(v.into())
     }
+
+    pub fn emissive(v: impl Into<Vector>) -> Material {
+        Material::Emissive(v.into())
+    }
     impl ShapeKind {
         pub fn with_mat(self, material: MaterialRef) -> Shape {
             Shape {
@@ -68,6 +74,11 @@ mod tests {
         save_world("scene_two", scene_two())
     }
 
+    #[test]
+    fn create_cornell_box() {
+        save_world("cornell_box", cornell_box())
+    }
+
     fn random_color(rng: &mut TlsWyRand) -> Vector {
         Vector::new(
             rng.generate::<Real>(),
@@ -109,13 +120,95 @@ mod tests {
         scene
     }
 
+    pub fn cornell_box() -> Scene {
+        let mut scene = Scene::new(
+            Vector::new(278.0, 278.0, -800.0),
+            Vector::new(278.0, 278.0, 0.0),
+            40.0,
+        );
+
+        let red = scene.add_material(diffuse((0.65, 0.05, 0.05)));
+        let white = scene.add_material(diffuse((0.73, 0.73, 0.73)));
+        let green = scene.add_material(diffuse((0.12, 0.45, 0.15)));
+        let light = scene.add_material(emissive((15.0, 15.0, 15.0)));
+
+        // The box is closed behind the camera too, so no light from the sky gets in
+        let front = -1000.0;
+        let depth = Vector::new(0.0, 0.0, 555.0 - front);
+        let walls = [
+            (
+                Vector::new(555.0, 0.0, front),
+                Vector::Y * 555.0,
+                depth,
+                green,
+            ),
+            (Vector::new(0.0, 0.0, front), Vector::Y * 555.0, depth, red),
+            (
+                Vector::new(0.0, 0.0, front),
+                Vector::X * 555.0,
+                depth,
+                white,
+            ),
+            (
+                Vector::new(0.0, 555.0, front),
+                Vector::X * 555.0,
+                depth,
+                white,
+            ),
+            (
+                Vector::new(0.0, 0.0, 555.0),
+                Vector::X * 555.0,
+                Vector::Y * 555.0,
+                white,
+            ),
+            (
+                Vector::new(0.0, 0.0, front),
+                Vector::X * 555.0,
+                Vector::Y * 555.0,
+                white,
+            ),
+        ];
+        for (origin, u, v, material) in walls {
+            scene
+                .shapes
+                .push(ShapeKind::Quad(origin, u, v).with_mat(material));
+        }
+
+        scene.shapes.push(
+            ShapeKind::Quad(
+                Vector::new(343.0, 554.0, 332.0),
+                Vector::new(-130.0, 0.0, 0.0),
+                Vector::new(0.0, 0.0, -105.0),
+            )
+            .with_mat(light),
+        );
+
+        scene.shapes.push(
+            ShapeKind::Box(
+                Vector::new(130.0, 0.0, 65.0),
+                Vector::new(295.0, 165.0, 230.0),
+            )
+            .with_mat(white),
+        );
+        scene.shapes.push(
+            ShapeKind::Box(
+                Vector::new(265.0, 0.0, 295.0),
+                Vector::new(430.0, 330.0, 460.0),
+            )
+            .with_mat(white),
+        );
+
+        scene
+    }
+
     fn scene_one() -> Scene {
         let mut scene = Scene::new(Vector::new(13.0, 2.0, 3.0), Vector::ZERO, 20.0);
+        scene.environment = Some(Environment::Sky(Box::new(SkyParameters::default().into())));
 
         let ground_material = scene.add_material(diffuse((0.5, 0.5, 0.5)));
         scene
             .shapes
-            .push(ShapeKind::Sphere((0.0, -1000.0, 0.0).into(), 1000.0).with_mat(ground_material));
+            .push(ShapeKind::Plane(Vector::ZERO, Vector::Y).with_mat(ground_material));
 
         let mut rng = nanorand::tls_rng();
 
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hit::Hit;
+use crate::materials::MaterialRef;
+use crate::ray::Ray;
+use crate::{Real, Vector};
+
+// The ray is on the surface once it gets this close
+const HIT_DISTANCE: Real = 1e-4;
+const MAX_STEPS: usize = 512;
+// Twists and smooth unions make the distance overshoot a bit, so don't trust it fully
+const STEP_SCALE: Real = 0.8;
+
+// Tree of distance functions. Every primitive is centered at the origin, use Translate to move it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Sdf {
+    Sphere(Real),
+    Box(Vector),            // Half of the size along each axis
+    RoundBox(Vector, Real), // Half of the size and radius of the rounded edges
+    Torus(Real, Real),      // Distance to the center of the tube and tube radius, around Y
+    Translate(Vector, Box<Sdf>),
+    SmoothUnion(Real, Box<Sdf>, Box<Sdf>), // How far the blend between both reaches
+    Repeat(Vector, Box<Sdf>),              // Period along each axis, 0 doesn't repeat on that axis
+    Twist(Real, Box<Sdf>),                 // Radians around Y per unit of height
+}
+
+fn box_distance(point: Vector, half_size: Vector) -> Real {
+    let q = point.abs() - half_size;
+    q.max(Vector::ZERO).length() + q.max_element().min(0.0)
+}
+
+impl Sdf {
+    // Negative inside the surface
+    pub fn distance(&self, point: Vector) -> Real {
+        match self {
+            Sdf::Sphere(radius) => point.length() - radius,
+            Sdf::Box(half_size) => box_distance(point, *half_size),
+            Sdf::RoundBox(half_size, radius) => {
+                box_distance(point, *half_size - Vector::splat(*radius)) - radius
+            }
+            Sdf::Torus(radius, tube_radius) => {
+                let ring = (point.x * point.x + point.z * point.z).sqrt() - radius;
+                (ring * ring + point.y * point.y).sqrt() - tube_radius
+            }
+            Sdf::Translate(offset, sdf) => sdf.distance(point - *offset),
+            Sdf::SmoothUnion(blend, a, b) => {
+                let a = a.distance(point);
+                let b = b.distance(point);
+                if *blend <= 0.0 {
+                    return a.min(b);
+                }
+                let h = (0.5 + 0.5 * (b - a) / blend).clamp(0.0, 1.0);
+                b + (a - b) * h - blend * h * (1.0 - h)
+            }
+            Sdf::Repeat(period, sdf) => {
+                let repeated = point - *period * (point / *period).round();
+                // Axes with no period have a NaN after the division
+                let local = Vector::select(period.cmpgt(Vector::ZERO), repeated, point);
+                sdf.distance(local)
+            }
+            Sdf::Twist(rate, sdf) => {
+                let (sin, cos) = (rate * point.y).sin_cos();
+                let local = Vector::new(
+                    cos * point.x - sin * point.z,
+                    point.y,
+                    sin * point.x + cos * point.z,
+                );
+                sdf.distance(local)
+            }
+        }
+    }
+
+    // Points away from the surface, taken from the gradient with the tetrahedron technique
+    fn normal(&self, point: Vector) -> Vector {
+        const H: Real = HIT_DISTANCE * 0.5;
+        [
+            Vector::new(1.0, -1.0, -1.0),
+            Vector::new(-1.0, -1.0, 1.0),
+            Vector::new(-1.0, 1.0, -1.0),
+            Vector::new(1.0, 1.0, 1.0),
+        ]
+        .into_iter()
+        .map(|k| k * self.distance(point + k * H))
+        .sum::<Vector>()
+        .normalize()
+    }
+
+    // Sphere traces the ray between the distances where it is inside the bounds of the surface
+    pub fn hit(&self, ray: &Ray, t_start: Real, t_end: Real, material: MaterialRef) -> Option<Hit> {
+        // Rays that start inside look for the way out
+        let start = ray.point(t_start);
+        let start_distance = self.distance(start);
+        let side = if start_distance.abs() < HIT_DISTANCE {
+            // Right on the surface, the direction tells which side the ray is going to
+            self.normal(start).dot(ray.direction).signum()
+        } else {
+            start_distance.signum()
+        };
+
+        // A ray that starts on the surface has to get away from it before it can hit it again
+        let mut left_surface = false;
+        let mut t = t_start;
+
+        for _ in 0..MAX_STEPS {
+            let distance = side * self.distance(ray.point(t));
+
+            if distance < HIT_DISTANCE {
+                if left_surface {
+                    return Some(Hit::new(t, self.normal(ray.point(t)), (0.0, 0.0), material));
+                }
+            } else {
+                left_surface = true;
+            }
+
+            t += distance.max(HIT_DISTANCE) * STEP_SCALE;
+            if t > t_end {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    // Every point between the two distances where the line of the ray crosses the surface,
+    // sorted. Each search starts on the last crossing, on its other side. A line that starts
+    // inside counts its start as a crossing, so they still pair up into intervals
+    pub fn crossings(
+        &self,
+        ray: &Ray,
+        t_start: Real,
+        t_end: Real,
+        material: MaterialRef,
+    ) -> Vec<Hit> {
+        let mut crossings = Vec::new();
+        if self.distance(ray.point(t_start)) < 0.0 {
+            crossings.push(Hit::new(t_start, -ray.direction, (0.0, 0.0), material));
+        }
+
+        let mut t = t_start;
+        while let Some(hit) = self.hit(ray, t, t_end, material) {
+            t = hit.t;
+            crossings.push(hit);
+        }
+
+        crossings
+    }
+}
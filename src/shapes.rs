@@ -1,19 +1,63 @@
-use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 use crate::hit::Hit;
 use crate::materials::MaterialRef;
+use crate::polynomial::{solve_quadratic, solve_quartic};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sdf::Sdf;
+use crate::volume::DensityGrid;
 use crate::{Real, Vector};
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub type ShapeRef = usize;
+
+// Flat shapes are hit again right at their surface by the bounced ray, so skip anything closer than this
+const MIN_DISTANCE: Real = 1e-6;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference, // Everything in the first shape that is not in the second one
+}
+
+impl CsgOperation {
+    fn contains(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ShapeKind {
     Sphere(Vector, Real),
     Triangle(Vector, Vector, Vector),
+    Quad(Vector, Vector, Vector), // Origin and the two edges leaving it
+    Disk(Vector, Vector, Real),   // Center, normal and radius
+    Box(Vector, Vector),          // Min and max corners
+    Plane(Vector, Vector),        // Any point on it and its normal
+    Cylinder(Vector, Vector, Real), // Center of the bottom and top caps and radius
+    Cone(Vector, Vector, Real, Real), // Center of the bottom and top caps and their radii
+    Torus(Vector, Vector, Real, Real), // Center, axis, radius of the ring and of the tube
+    // Combination of two closed shapes, they use the material of the Shape that holds them
+    Csg(CsgOperation, Box<ShapeKind>, Box<ShapeKind>),
+    // Surface of a distance field, with the min and max corners of a box that holds all of it
+    Sdf(Sdf, Vector, Vector),
+    // Fog with the given density that fills a closed shape. Its material should be a phase function
+    Medium(Box<ShapeKind>, Real),
+    // Fog whose density comes from a grid, multiplied by a scale. It fills the bounds of the grid
+    VoxelMedium(DensityGrid, Real),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 
 pub struct Shape {
+    #[serde(deserialize_with = "closed_where_needed")]
     pub kind: ShapeKind,
     pub material: MaterialRef,
 }
@@ -45,10 +89,7 @@ impl Shape {
                     }
                 }
 
-                let point = ray.point(root);
-                let normal = (point - center) / radious;
-
-                Some(Hit::new(root, normal, self.material))
+                Some(sphere_hit(ray, center, radious, root, self.material))
             }
             ShapeKind::Triangle(a, b, c) => {
                 // Code stolen from https://docs.rs/bvh/latest/src/bvh/ray.rs.html#289-340
@@ -99,11 +140,749 @@ impl Shape {
                 let dist = a_to_c.dot(v_vec) * inv_det;
 
                 if dist > Real::EPSILON {
-                    Some(Hit::new(dist, a_to_b.cross(a_to_c), self.material))
+                    Some(Hit::new(dist, a_to_b.cross(a_to_c), (u, v), self.material))
                 } else {
                     None
                 }
             }
+            ShapeKind::Quad(origin, u, v) => {
+                let normal = u.cross(v);
+                let t = plane_distance(ray, origin, normal)?;
+
+                // Express the hit point in the (u, v) basis of the quad
+                let to_point = ray.point(t) - origin;
+                let w = normal / normal.length_squared();
+                let alpha = w.dot(to_point.cross(v));
+                let beta = w.dot(u.cross(to_point));
+
+                if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+                    return None;
+                }
+
+                Some(Hit::new(
+                    t,
+                    normal.normalize(),
+                    (alpha, beta),
+                    self.material,
+                ))
+            }
+            ShapeKind::Disk(center, normal, radius) => {
+                let t = plane_distance(ray, center, normal)?;
+
+                let to_point = ray.point(t) - center;
+                let distance = to_point.length();
+                if distance > radius {
+                    return None;
+                }
+
+                let normal = normal.normalize();
+                let (tangent, bitangent) = normal.any_orthonormal_pair();
+                let angle = to_point.dot(bitangent).atan2(to_point.dot(tangent));
+                let uv = (0.5 + angle / (2.0 * PI), distance / radius);
+
+                Some(Hit::new(t, normal, uv, self.material))
+            }
+            ShapeKind::Plane(point, normal) => {
+                let t = plane_distance(ray, point, normal)?;
+
+                let normal = normal.normalize();
+                let (tangent, bitangent) = normal.any_orthonormal_pair();
+                let to_point = ray.point(t) - point;
+                let uv = (to_point.dot(tangent), to_point.dot(bitangent));
+
+                Some(Hit::new(t, normal, uv, self.material))
+            }
+            ShapeKind::Cylinder(bottom, top, radius) => nearest(frustum_crossings(
+                ray,
+                bottom,
+                top,
+                radius,
+                radius,
+                self.material,
+            )),
+            ShapeKind::Cone(bottom, top, bottom_radius, top_radius) => nearest(frustum_crossings(
+                ray,
+                bottom,
+                top,
+                bottom_radius,
+                top_radius,
+                self.material,
+            )),
+            ShapeKind::Torus(center, axis, radius, tube_radius) => nearest(torus_crossings(
+                ray,
+                center,
+                axis,
+                radius,
+                tube_radius,
+                self.material,
+            )),
+            ShapeKind::Box(min, max) => {
+                let (t_enter, t_exit) = box_span(ray, min, max)?;
+
+                if t_exit < MIN_DISTANCE {
+                    return None;
+                }
+
+                // From the inside the first face we can hit is the exit one
+                let t = if t_enter < MIN_DISTANCE {
+                    t_exit
+                } else {
+                    t_enter
+                };
+
+                Some(box_hit(ray, min, max, t, self.material))
+            }
+            ShapeKind::Sdf(ref sdf, min, max) => {
+                let (t_enter, t_exit) = box_span(ray, min, max)?;
+
+                if t_exit < MIN_DISTANCE {
+                    return None;
+                }
+
+                sdf.hit(ray, t_enter.max(MIN_DISTANCE), t_exit, self.material)
+            }
+            // Media have no surface, the integrators sample where the light scatters in them
+            ShapeKind::Medium(..) | ShapeKind::VoxelMedium(..) => None,
+            ShapeKind::Csg(..) => self
+                .kind
+                .intervals(ray, self.material)
+                .into_iter()
+                .flat_map(|(enter, exit)| [enter, exit])
+                .find(|hit| hit.t > MIN_DISTANCE),
+        }
+    }
+
+    // Stretches of the ray inside a medium, from where it goes in to where it comes out. Empty
+    // for the other shapes
+    pub fn medium_spans(&self, ray: &Ray) -> Vec<(Real, Real)> {
+        let spans = match self.kind {
+            // The ray can cross a concave boundary several times
+            ShapeKind::Medium(ref boundary, _) => boundary
+                .intervals(ray, self.material)
+                .into_iter()
+                .map(|(enter, exit)| (enter.t, exit.t))
+                .collect(),
+            ShapeKind::VoxelMedium(ref grid, _) => {
+                box_span(ray, grid.min, grid.max).into_iter().collect()
+            }
+            _ => Vec::new(),
+        };
+        spans
+            .into_iter()
+            .filter(|(_, exit)| *exit >= MIN_DISTANCE)
+            .map(|(enter, exit)| (enter.max(0.0), exit))
+            .collect()
+    }
+
+    // Where the light first scatters in the medium along the ray, if it does before `t_max`
+    pub fn sample_medium(&self, ray: &Ray, t_max: Real, sampler: &mut dyn Sampler) -> Option<Hit> {
+        for (start, end) in self.medium_spans(ray) {
+            let end = end.min(t_max);
+            if start >= end {
+                continue;
+            }
+
+            let t = match self.kind {
+                ShapeKind::Medium(_, density) => {
+                    let distance = -(1.0 - sampler.get_1d()).ln() / density;
+                    Some(start + distance).filter(|t| *t < end)
+                }
+                ShapeKind::VoxelMedium(ref grid, scale) => {
+                    grid.sample(ray, start, end, scale, sampler)
+                }
+                _ => None,
+            };
+            if let Some(t) = t {
+                // There is no surface, face the ray so it always counts as coming from outside
+                return Some(Hit::new(t, -ray.direction, (0.0, 0.0), self.material));
+            }
+        }
+
+        None
+    }
+
+    // Fraction of the light that makes it through the medium along the ray up to `t_max`
+    pub fn transmittance(&self, ray: &Ray, t_max: Real, sampler: &mut dyn Sampler) -> Real {
+        self.medium_spans(ray)
+            .into_iter()
+            .map(|(start, end)| (start, end.min(t_max)))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| match self.kind {
+                ShapeKind::Medium(_, density) => (-density * (end - start)).exp(),
+                ShapeKind::VoxelMedium(ref grid, scale) => {
+                    grid.transmittance(ray, start, end, scale, sampler)
+                }
+                _ => 1.0,
+            })
+            .product()
+    }
+}
+
+// Scenes that combine shapes or fill them with fog when they have nothing inside would render
+// something else than they say
+fn closed_where_needed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ShapeKind, D::Error> {
+    let kind = ShapeKind::deserialize(deserializer)?;
+    kind.check_closed().map_err(de::Error::custom)?;
+    Ok(kind)
+}
+
+impl ShapeKind {
+    // Whether the shape has an inside, the ones that don't have no intervals
+    fn is_closed(&self) -> bool {
+        match self {
+            ShapeKind::Sphere(..)
+            | ShapeKind::Box(..)
+            | ShapeKind::Cylinder(..)
+            | ShapeKind::Cone(..)
+            | ShapeKind::Torus(..)
+            | ShapeKind::Sdf(..) => true,
+            ShapeKind::Csg(_, a, b) => a.is_closed() && b.is_closed(),
+            ShapeKind::Triangle(..)
+            | ShapeKind::Quad(..)
+            | ShapeKind::Disk(..)
+            | ShapeKind::Plane(..)
+            | ShapeKind::Medium(..)
+            | ShapeKind::VoxelMedium(..) => false,
+        }
+    }
+
+    fn check_closed(&self) -> Result<(), String> {
+        match self {
+            ShapeKind::Csg(_, a, b) => {
+                match [a, b].into_iter().find(|operand| !operand.is_closed()) {
+                    Some(operand) => Err(format!(
+                        "CSG only works on closed shapes, not on {operand:?}"
+                    )),
+                    None => Ok(()),
+                }
+            }
+            ShapeKind::Medium(boundary, _) if !boundary.is_closed() => {
+                Err(format!("Media only fill closed shapes, not {boundary:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Media are filled with fog instead of being bounded by a surface
+    pub fn is_medium(&self) -> bool {
+        matches!(self, ShapeKind::Medium(..) | ShapeKind::VoxelMedium(..))
+    }
+
+    // Surface area of the shapes that can be used as area lights
+    pub fn area(&self) -> Option<Real> {
+        match *self {
+            ShapeKind::Sphere(_, radius) => Some(4.0 * PI * radius * radius),
+            ShapeKind::Triangle(a, b, c) => Some((b - a).cross(c - a).length() * 0.5),
+            ShapeKind::Quad(_, u, v) => Some(u.cross(v).length()),
+            ShapeKind::Disk(_, _, radius) => Some(PI * radius * radius),
+            _ => None,
+        }
+    }
+
+    // Point and normal spread uniformly over the surface, from two numbers in [0, 1)
+    pub fn sample(&self, u: Real, v: Real) -> Option<(Vector, Vector)> {
+        match *self {
+            ShapeKind::Sphere(center, radius) => {
+                let z = 1.0 - 2.0 * u;
+                let ring = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * v;
+                let normal = Vector::new(ring * phi.cos(), ring * phi.sin(), z);
+                Some((center + normal * radius, normal))
+            }
+            ShapeKind::Triangle(a, b, c) => {
+                let root = u.sqrt();
+                let (weight_b, weight_c) = (v * root, (1.0 - v) * root);
+                let point = a + (b - a) * weight_b + (c - a) * weight_c;
+                Some((point, (b - a).cross(c - a).normalize()))
+            }
+            ShapeKind::Quad(origin, edge_u, edge_v) => Some((
+                origin + edge_u * u + edge_v * v,
+                edge_u.cross(edge_v).normalize(),
+            )),
+            ShapeKind::Disk(center, normal, radius) => {
+                let normal = normal.normalize();
+                let (tangent, bitangent) = normal.any_orthonormal_pair();
+                let (sin, cos) = (2.0 * PI * v).sin_cos();
+                let point = center + (tangent * cos + bitangent * sin) * radius * u.sqrt();
+                Some((point, normal))
+            }
+            _ => None,
+        }
+    }
+
+    // Sorted, disjoint stretches of the line of the ray that are inside the shape, as the hits
+    // where the line enters and leaves it. Shapes that don't enclose anything have none
+    fn intervals(&self, ray: &Ray, material: MaterialRef) -> Vec<(Hit, Hit)> {
+        match *self {
+            ShapeKind::Sphere(center, radius) => {
+                let o_c = ray.origin - center;
+                let roots = solve_quadratic(
+                    ray.direction.length_squared(),
+                    2.0 * ray.direction.dot(o_c),
+                    o_c.length_squared() - radius * radius,
+                );
+                as_intervals(
+                    roots
+                        .into_iter()
+                        .map(|t| sphere_hit(ray, center, radius, t, material))
+                        .collect(),
+                )
+            }
+            ShapeKind::Box(min, max) => match box_span(ray, min, max) {
+                Some((t_enter, t_exit)) => vec![(
+                    box_hit(ray, min, max, t_enter, material),
+                    box_hit(ray, min, max, t_exit, material),
+                )],
+                None => Vec::new(),
+            },
+            ShapeKind::Cylinder(bottom, top, radius) => as_intervals(frustum_crossings(
+                ray, bottom, top, radius, radius, material,
+            )),
+            ShapeKind::Cone(bottom, top, bottom_radius, top_radius) => as_intervals(
+                frustum_crossings(ray, bottom, top, bottom_radius, top_radius, material),
+            ),
+            ShapeKind::Torus(center, axis, radius, tube_radius) => as_intervals(torus_crossings(
+                ray,
+                center,
+                axis,
+                radius,
+                tube_radius,
+                material,
+            )),
+            ShapeKind::Sdf(ref sdf, min, max) => match box_span(ray, min, max) {
+                Some((t_enter, t_exit)) => {
+                    as_intervals(sdf.crossings(ray, t_enter, t_exit, material))
+                }
+                None => Vec::new(),
+            },
+            ShapeKind::Csg(operation, ref a, ref b) => combine(
+                operation,
+                a.intervals(ray, material),
+                b.intervals(ray, material),
+            ),
+            ShapeKind::Triangle(..)
+            | ShapeKind::Quad(..)
+            | ShapeKind::Disk(..)
+            | ShapeKind::Plane(..)
+            | ShapeKind::Medium(..)
+            | ShapeKind::VoxelMedium(..) => Vec::new(),
         }
     }
 }
+
+// Pairs up the sorted crossings of a closed surface. A ray grazing the surface can report an odd
+// number of them, the unpaired one is dropped
+fn as_intervals(crossings: Vec<Hit>) -> Vec<(Hit, Hit)> {
+    crossings
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
+}
+
+// Walks the boundaries of both shapes in order, keeping the ones where being inside the
+// combination changes
+fn combine(operation: CsgOperation, a: Vec<(Hit, Hit)>, b: Vec<(Hit, Hit)>) -> Vec<(Hit, Hit)> {
+    let mut boundaries = a
+        .into_iter()
+        .flat_map(|(enter, exit)| [(enter, true, true), (exit, true, false)])
+        .chain(
+            b.into_iter()
+                .flat_map(|(enter, exit)| [(enter, false, true), (exit, false, false)]),
+        )
+        .collect::<Vec<_>>();
+    boundaries.sort_unstable_by(|(a, ..), (b, ..)| a.t.partial_cmp(&b.t).unwrap());
+
+    let (mut in_a, mut in_b, mut inside) = (false, false, false);
+    let mut enter = None;
+    let mut intervals = Vec::new();
+
+    for (hit, from_a, entering) in boundaries {
+        if from_a {
+            in_a = entering;
+        } else {
+            in_b = entering;
+        }
+
+        if operation.contains(in_a, in_b) != inside {
+            inside = !inside;
+
+            // What is carved out of the first shape faces the other way
+            let hit = if !from_a && operation == CsgOperation::Difference {
+                hit.flipped()
+            } else {
+                hit
+            };
+
+            if inside {
+                enter = Some(hit);
+            } else if let Some(enter) = enter.take() {
+                intervals.push((enter, hit));
+            }
+        }
+    }
+
+    intervals
+}
+
+fn sphere_hit(ray: &Ray, center: Vector, radius: Real, t: Real, material: MaterialRef) -> Hit {
+    let normal = (ray.point(t) - center) / radius;
+    let uv = (
+        0.5 + normal.z.atan2(normal.x) / (2.0 * PI),
+        normal.y.clamp(-1.0, 1.0).acos() / PI,
+    );
+
+    Hit::new(t, normal, uv, material)
+}
+
+// Distances along the line of the ray where it enters and leaves the box
+fn box_span(ray: &Ray, min: Vector, max: Vector) -> Option<(Real, Real)> {
+    let ray_dir_recip = ray.direction.recip();
+    let t0 = (min - ray.origin) * ray_dir_recip;
+    let t1 = (max - ray.origin) * ray_dir_recip;
+
+    let t_enter = t0.min(t1).max_element();
+    let t_exit = t0.max(t1).min_element();
+
+    (t_enter <= t_exit).then_some((t_enter, t_exit))
+}
+
+fn box_hit(ray: &Ray, min: Vector, max: Vector, t: Real, material: MaterialRef) -> Hit {
+    // The face we hit is on the slab whose side the ray crosses at `t`. The point alone can't
+    // tell for boxes that are flat along an axis
+    let ray_dir_recip = ray.direction.recip();
+    let gap = |side: Vector| {
+        let gap = ((side - ray.origin) * ray_dir_recip - t).abs();
+        // Rays along a side that start on it never cross it
+        Vector::select(gap.is_nan_mask(), Vector::splat(Real::INFINITY), gap)
+    };
+    let (gap_min, gap_max) = (gap(min), gap(max));
+    let gap = gap_min.min(gap_max);
+    let (axis, other_1, other_2) = if gap.x < gap.y && gap.x < gap.z {
+        (0, 1, 2)
+    } else if gap.y < gap.z {
+        (1, 2, 0)
+    } else {
+        (2, 0, 1)
+    };
+
+    let half_size = (max - min) * 0.5;
+    let mut normal = Vector::ZERO;
+    normal[axis] = if half_size[axis] == 0.0 {
+        // Both sides are the same, face the ray
+        -ray.direction[axis].signum()
+    } else if gap_min[axis] < gap_max[axis] {
+        -1.0
+    } else {
+        1.0
+    };
+
+    let offset = ray.point(t) - (min + half_size);
+    let local = Vector::select(
+        half_size.cmpgt(Vector::ZERO),
+        offset / half_size,
+        Vector::ZERO,
+    );
+    let uv = ((local[other_1] + 1.0) * 0.5, (local[other_2] + 1.0) * 0.5);
+
+    Hit::new(t, normal, uv, material)
+}
+
+// Distance along the ray to the plane through `point`, if it's in front of the ray
+fn plane_distance(ray: &Ray, point: Vector, normal: Vector) -> Option<Real> {
+    let denominator = normal.dot(ray.direction);
+    if denominator.abs() < Real::EPSILON {
+        return None;
+    }
+
+    let t = normal.dot(point - ray.origin) / denominator;
+    (t > MIN_DISTANCE).then_some(t)
+}
+
+// First of the crossings that is in front of the ray
+fn nearest(crossings: Vec<Hit>) -> Option<Hit> {
+    crossings.into_iter().find(|hit| hit.t > MIN_DISTANCE)
+}
+
+// Orthonormal frame (tangent, axis, bitangent) with the given axis as local Y
+fn local_frame(axis: Vector) -> (Vector, Vector, Vector) {
+    let axis = axis.normalize();
+    let (tangent, bitangent) = axis.any_orthonormal_pair();
+    (tangent, axis, bitangent)
+}
+
+// Every point where the line of the ray crosses a capped cone frustum, sorted by distance.
+// A cylinder is just a frustum with both radii equal
+fn frustum_crossings(
+    ray: &Ray,
+    bottom: Vector,
+    top: Vector,
+    bottom_radius: Real,
+    top_radius: Real,
+    material: MaterialRef,
+) -> Vec<Hit> {
+    let height = (top - bottom).length();
+    let (tangent, axis, bitangent) = local_frame(top - bottom);
+    let slope = (top_radius - bottom_radius) / height;
+
+    // Split the ray into its components along the axis and across it
+    let origin = ray.origin - bottom;
+    let origin_along = origin.dot(axis);
+    let direction_along = ray.direction.dot(axis);
+    let origin_across = origin - axis * origin_along;
+    let direction_across = ray.direction - axis * direction_along;
+
+    // The side is where the distance to the axis equals the radius at that height
+    let radius_at_origin = bottom_radius + slope * origin_along;
+    let a = direction_across.length_squared() - (slope * direction_along).powi(2);
+    let b =
+        2.0 * (origin_across.dot(direction_across) - slope * radius_at_origin * direction_along);
+    let c = origin_across.length_squared() - radius_at_origin * radius_at_origin;
+
+    let mut crossings = Vec::new();
+
+    for t in solve_quadratic(a, b, c) {
+        let height_at = origin_along + t * direction_along;
+        // The quadratic also finds the mirrored cone past the tip
+        if !(0.0..=height).contains(&height_at) || bottom_radius + slope * height_at < 0.0 {
+            continue;
+        }
+
+        let across = origin_across + direction_across * t;
+        let outwards = across.normalize();
+        let normal = (outwards - axis * slope).normalize();
+        let angle = across.dot(bitangent).atan2(across.dot(tangent));
+        let uv = (0.5 + angle / (2.0 * PI), height_at / height);
+
+        crossings.push(Hit::new(t, normal, uv, material));
+    }
+
+    if direction_along.abs() > Real::EPSILON {
+        for (cap_height, cap_radius, normal) in
+            [(0.0, bottom_radius, -axis), (height, top_radius, axis)]
+        {
+            let t = (cap_height - origin_along) / direction_along;
+            let across = origin_across + direction_across * t;
+            let distance = across.length();
+            if distance <= cap_radius {
+                let angle = across.dot(bitangent).atan2(across.dot(tangent));
+                let uv = (0.5 + angle / (2.0 * PI), distance / cap_radius);
+                crossings.push(Hit::new(t, normal, uv, material));
+            }
+        }
+    }
+
+    crossings.sort_unstable_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+    crossings
+}
+
+// Every point where the line of the ray crosses a torus, sorted by distance
+fn torus_crossings(
+    ray: &Ray,
+    center: Vector,
+    axis: Vector,
+    radius: Real,
+    tube_radius: Real,
+    material: MaterialRef,
+) -> Vec<Hit> {
+    let (tangent, axis, bitangent) = local_frame(axis);
+    let to_local = |v: Vector| Vector::new(v.dot(tangent), v.dot(axis), v.dot(bitangent));
+
+    // Quartic roots lose precision far away from the torus, so start the ray close to it
+    let to_center = center - ray.origin;
+    let skip = (to_center.dot(ray.direction) - radius - tube_radius).max(0.0);
+
+    let origin = to_local(ray.origin + ray.direction * skip - center);
+    let direction = to_local(ray.direction);
+
+    // Expand (|p|^2 + R^2 - r^2)^2 = 4 R^2 (p.x^2 + p.z^2) with p = origin + t * direction
+    let four_r2 = 4.0 * radius * radius;
+    let dd = direction.length_squared();
+    let od = origin.dot(direction);
+    let oo = origin.length_squared();
+    let e = oo + radius * radius - tube_radius * tube_radius;
+
+    let roots = solve_quartic(
+        dd * dd,
+        4.0 * dd * od,
+        4.0 * od * od + 2.0 * dd * e - four_r2 * (dd - direction.y * direction.y),
+        4.0 * od * e - 2.0 * four_r2 * (od - origin.y * direction.y),
+        e * e - four_r2 * (oo - origin.y * origin.y),
+    );
+
+    roots
+        .into_iter()
+        .map(|t| {
+            let point = origin + direction * t;
+            // The normal points away from the closest point of the circle in the middle of the tube
+            let ring = Vector::new(point.x, 0.0, point.z).normalize() * radius;
+            let local_normal = (point - ring).normalize();
+            let normal =
+                tangent * local_normal.x + axis * local_normal.y + bitangent * local_normal.z;
+
+            let distance_to_axis = (point.x * point.x + point.z * point.z).sqrt();
+            let uv = (
+                0.5 + point.z.atan2(point.x) / (2.0 * PI),
+                0.5 + point.y.atan2(distance_to_axis - radius) / (2.0 * PI),
+            );
+
+            Hit::new(t + skip, normal, uv, material)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+
+    // The shape as it comes back from a scene file
+    fn reload(shape: Shape) -> serde_json::Result<Shape> {
+        serde_json::from_str(&serde_json::to_string(&shape).unwrap())
+    }
+
+    fn half_sphere(operation: CsgOperation) -> Shape {
+        Shape {
+            kind: ShapeKind::Csg(
+                operation,
+                Box::new(ShapeKind::Sphere(Vector::ZERO, 1.0)),
+                Box::new(ShapeKind::Box(Vector::new(0.0, -2.0, -2.0), Vector::splat(2.0))),
+            ),
+            material: 0,
+        }
+    }
+
+    #[test]
+    fn flat_box() {
+        let floor = Shape {
+            kind: ShapeKind::Box(Vector::new(-1.0, 0.0, -1.0), Vector::new(1.0, 0.0, 1.0)),
+            material: 0,
+        };
+
+        for (origin, direction) in [(Vector::Y, -Vector::Y), (-Vector::Y, Vector::Y)] {
+            let ray = Ray::new(origin + Vector::new(0.5, 0.0, 0.0), direction);
+            let hit = floor.hit(&ray).unwrap();
+            assert!((hit.t - 1.0).abs() < 1e-9);
+            let info = hit.get_hit_info(&ray);
+            assert_eq!(info.normal, -direction);
+            assert!(info.front_face);
+            assert!((info.uv.0 - 0.5).abs() < 1e-9 && (info.uv.1 - 0.75).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn csg_difference() {
+        let shape = half_sphere(CsgOperation::Difference);
+
+        let hit = shape.hit(&Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::X));
+        assert!((hit.unwrap().t - 4.0).abs() < 1e-9);
+
+        // The first thing in the way is the cut, seen from outside
+        let ray = Ray::new(Vector::new(5.0, 0.0, 0.0), -Vector::X);
+        let hit = shape.hit(&ray).unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-9);
+        assert!(hit.front_face(&ray));
+
+        let ray = Ray::new(Vector::new(0.5, 5.0, 0.0), -Vector::Y);
+        assert!(shape.hit(&ray).is_none());
+    }
+
+    // The box cut by a ball from a distance field, or only the part of the ball inside the box
+    fn half_ball(operation: CsgOperation) -> Shape {
+        Shape {
+            kind: ShapeKind::Csg(
+                operation,
+                Box::new(ShapeKind::Box(
+                    Vector::new(0.0, -2.0, -2.0),
+                    Vector::splat(2.0),
+                )),
+                Box::new(ShapeKind::Sdf(
+                    Sdf::Sphere(1.0),
+                    Vector::splat(-1.5),
+                    Vector::splat(1.5),
+                )),
+            ),
+            material: 0,
+        }
+    }
+
+    #[test]
+    fn csg_sdf() {
+        // Sphere tracing stops close to the surface, not on it
+        let close = |hit: Option<Hit>, t: Real| (hit.unwrap().t - t).abs() < 1e-3;
+
+        let from_left = Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::X);
+        let from_right = Ray::new(Vector::new(5.0, 0.0, 0.0), -Vector::X);
+
+        let cut = half_ball(CsgOperation::Difference);
+        assert!(close(cut.hit(&from_right), 3.0));
+        // The box starts inside the ball, the first thing in the way is the hole seen from outside
+        let hit = cut.hit(&from_left);
+        assert!(close(hit, 6.0));
+        assert!(hit.unwrap().front_face(&from_left));
+
+        let inside = half_ball(CsgOperation::Intersection);
+        assert!(close(inside.hit(&from_left), 5.0));
+        assert!(close(inside.hit(&from_right), 4.0));
+        let ray = Ray::new(Vector::new(-0.5, 5.0, 0.0), -Vector::Y);
+        assert!(inside.hit(&ray).is_none());
+    }
+
+    #[test]
+    fn csg_needs_closed_shapes() {
+        let load = |operand: ShapeKind| {
+            let shape = Shape {
+                kind: ShapeKind::Csg(
+                    CsgOperation::Union,
+                    Box::new(ShapeKind::Sphere(Vector::ZERO, 1.0)),
+                    Box::new(operand),
+                ),
+                material: 0,
+            };
+            reload(shape)
+        };
+
+        assert!(load(ShapeKind::Box(Vector::ZERO, Vector::ONE)).is_ok());
+        let ball = ShapeKind::Sdf(Sdf::Sphere(1.0), Vector::NEG_ONE, Vector::ONE);
+        assert!(load(ball).is_ok());
+        assert!(load(ShapeKind::Quad(Vector::ZERO, Vector::X, Vector::Y)).is_err());
+        // Further down too
+        let cut = ShapeKind::Csg(
+            CsgOperation::Difference,
+            Box::new(ShapeKind::Sphere(Vector::ZERO, 1.0)),
+            Box::new(ShapeKind::Plane(Vector::ZERO, Vector::Y)),
+        );
+        assert!(load(cut).is_err());
+    }
+
+    #[test]
+    fn medium_in_sdf() {
+        let fog = |boundary: ShapeKind| Shape {
+            kind: ShapeKind::Medium(Box::new(boundary), 0.5),
+            material: 0,
+        };
+        let ball = fog(ShapeKind::Sdf(
+            Sdf::Sphere(1.0),
+            Vector::splat(-1.5),
+            Vector::splat(1.5),
+        ));
+
+        let ray = Ray::new(Vector::new(-5.0, 0.0, 0.0), Vector::X);
+        let mut sampler = SamplerKind::Independent.create(1, 1);
+        let transmittance = ball.transmittance(&ray, Real::INFINITY, sampler.as_mut());
+        assert!((transmittance - Real::exp(-1.0)).abs() < 1e-3);
+
+        assert!(reload(ball).is_ok());
+        assert!(reload(fog(ShapeKind::Quad(Vector::ZERO, Vector::X, Vector::Y))).is_err());
+    }
+
+    #[test]
+    fn csg_intersection() {
+        let shape = half_sphere(CsgOperation::Intersection);
+
+        let hit = shape.hit(&Ray::new(Vector::new(5.0, 0.0, 0.0), -Vector::X));
+        assert!((hit.unwrap().t - 4.0).abs() < 1e-9);
+
+        let ray = Ray::new(Vector::new(-0.5, 5.0, 0.0), -Vector::Y);
+        assert!(shape.hit(&ray).is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{environment::SkySample, Real, Vector};
+
+// Preetham gives luminances in kcd/m², this brings a clear day down to about the brightness of
+// the gradient
+const SCALE: Real = 0.05;
+// Illuminance of the sun outside the atmosphere, in klx
+const SUN_ILLUMINANCE: Real = 128.0;
+
+// Chromaticity at the zenith as a polynomial of the turbidity (rows) and the angle of the sun
+// (columns)
+const ZENITH_X: [[Real; 4]; 3] = [
+    [0.00166, -0.00375, 0.00209, 0.0],
+    [-0.02903, 0.06377, -0.03202, 0.00394],
+    [0.11693, -0.21196, 0.06052, 0.25886],
+];
+const ZENITH_Y: [[Real; 4]; 3] = [
+    [0.00275, -0.00610, 0.00317, 0.0],
+    [-0.04214, 0.08970, -0.04153, 0.00516],
+    [0.15346, -0.26756, 0.06670, 0.26688],
+];
+
+// How the sky is written in the config
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SkyParameters {
+    sun_direction: Vector, // Towards the sun
+    turbidity: Real,       // Haze, from 2 for a clear day to 10 for a hazy one
+    ground_albedo: Vector, // Of the ground seen below the horizon
+    sun_size: Real,        // Angular radius of the sun disk, in degrees
+    intensity: Real,
+}
+
+impl Default for SkyParameters {
+    fn default() -> Self {
+        Self {
+            sun_direction: Vector::new(1.0, 1.0, 0.5),
+            turbidity: 3.0,
+            ground_albedo: Vector::splat(0.3),
+            sun_size: 0.27,
+            intensity: 1.0,
+        }
+    }
+}
+
+// Daylight sky from the model of Preetham, Shirley and Smits (1999), plus the disk of the sun
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "SkyParameters", into = "SkyParameters")]
+pub struct Sky {
+    parameters: SkyParameters,
+    sun: Vector,
+    // Perez coefficients and zenith values for the luminance and the x and y chromaticities
+    perez: [[Real; 5]; 3],
+    zenith: [Real; 3],
+    sun_radiance: Vector,
+    cos_sun_size: Real,
+    ground: Vector,
+}
+
+impl From<SkyParameters> for Sky {
+    fn from(parameters: SkyParameters) -> Self {
+        let sun = parameters.sun_direction.normalize();
+        let turbidity = parameters.turbidity.clamp(1.7, 10.0);
+        // The model only holds with the sun above the horizon
+        let theta_sun = sun.y.clamp(0.0, 1.0).acos();
+        let cos_sun_size = parameters.sun_size.to_radians().cos();
+
+        // Spread over the disk so the light reaching the scene doesn't depend on its size
+        let sun_radiance = if sun.y > 0.0 {
+            transmittance(turbidity, sun.y) * SUN_ILLUMINANCE / cone_solid_angle(cos_sun_size)
+        } else {
+            Vector::ZERO
+        };
+
+        let mut sky = Self {
+            sun,
+            perez: perez_coefficients(turbidity),
+            zenith: zenith(turbidity, theta_sun),
+            sun_radiance: sun_radiance * SCALE * parameters.intensity,
+            cos_sun_size,
+            ground: Vector::ZERO,
+            parameters,
+        };
+        // Lit by the sky and the sun, reflecting it evenly
+        sky.ground = sky.parameters.ground_albedo / PI * sky.irradiance();
+        sky
+    }
+}
+
+impl From<Sky> for SkyParameters {
+    fn from(sky: Sky) -> Self {
+        sky.parameters
+    }
+}
+
+// Coefficients of the Perez formula for the luminance and the chromaticities, linear in the
+// turbidity
+fn perez_coefficients(t: Real) -> [[Real; 5]; 3] {
+    [
+        [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ],
+        [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ],
+        [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ],
+    ]
+}
+
+// Distribution of the light over the sky, by the angle from the zenith and from the sun
+fn perez([a, b, c, d, e]: [Real; 5], cos_theta: Real, gamma: Real) -> Real {
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+fn zenith(t: Real, theta_sun: Real) -> [Real; 3] {
+    let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+    let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+    let thetas = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+    let chromaticity = |m: [[Real; 4]; 3]| {
+        let row = |i: usize| m[i].iter().zip(thetas).map(|(a, b)| a * b).sum::<Real>();
+        t * t * row(0) + t * row(1) + row(2)
+    };
+    [luminance, chromaticity(ZENITH_X), chromaticity(ZENITH_Y)]
+}
+
+// Share of the sunlight that makes it through the air, at 680, 550 and 440 nm for the red, green
+// and blue channels. Rayleigh scattering by the air plus the haze (Ångström's formula)
+fn transmittance(turbidity: Real, cos_theta: Real) -> Vector {
+    // Kasten and Young (1989), the air gets thicker towards the horizon
+    let zenith_angle = cos_theta.acos().to_degrees();
+    let air_mass = 1.0 / (cos_theta + 0.50572 * (96.07995 - zenith_angle).powf(-1.6364));
+    let haze = 0.04608 * turbidity - 0.04586;
+    let optical_depth =
+        |wavelength: Real| 0.008735 * wavelength.powf(-4.08) + haze * wavelength.powf(-1.3);
+
+    let depth = Vector::new(
+        optical_depth(0.68),
+        optical_depth(0.55),
+        optical_depth(0.44),
+    );
+    (depth * -air_mass).to_array().map(Real::exp).into()
+}
+
+fn cone_solid_angle(cos_theta_max: Real) -> Real {
+    2.0 * PI * (1.0 - cos_theta_max)
+}
+
+fn xyy_to_rgb(luminance: Real, x: Real, y: Real) -> Vector {
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Vector::new(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    )
+    .max(Vector::ZERO)
+}
+
+impl Sky {
+    // Without the sun disk
+    fn sky_radiance(&self, direction: Vector) -> Vector {
+        // Right at the horizon the formula blows up
+        let cos_theta = direction.y.max(0.01);
+        let gamma = direction.dot(self.sun).clamp(-1.0, 1.0).acos();
+        let theta_sun = self.sun.y.clamp(0.0, 1.0).acos();
+
+        let [luminance, x, y] = [0, 1, 2].map(|i| {
+            let relative =
+                perez(self.perez[i], cos_theta, gamma) / perez(self.perez[i], 1.0, theta_sun);
+            self.zenith[i] * relative
+        });
+        xyy_to_rgb(luminance, x, y) * SCALE * self.parameters.intensity
+    }
+
+    fn in_sun(&self, direction: Vector) -> bool {
+        direction.dot(self.sun) >= self.cos_sun_size
+    }
+
+    // Reaching the ground, numerically over the sky and exactly for the sun
+    fn irradiance(&self) -> Vector {
+        const STEPS: usize = 32;
+        let step = PI / 2.0 / STEPS as Real;
+
+        let mut irradiance = Vector::ZERO;
+        for i in 0..STEPS {
+            let theta = (i as Real + 0.5) * step;
+            for j in 0..4 * STEPS {
+                let phi = (j as Real + 0.5) * step;
+                let direction = Vector::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let area = theta.sin() * step * step;
+                irradiance += self.sky_radiance(direction) * theta.cos() * area;
+            }
+        }
+
+        irradiance + self.sun_radiance * cone_solid_angle(self.cos_sun_size) * self.sun.y.max(0.0)
+    }
+
+    pub fn radiance(&self, direction: Vector) -> Vector {
+        let direction = direction.normalize();
+        if direction.y < 0.0 {
+            return self.ground;
+        }
+
+        let sun = if self.in_sun(direction) {
+            self.sun_radiance
+        } else {
+            Vector::ZERO
+        };
+        self.sky_radiance(direction) + sun
+    }
+
+    // Direction towards the sun disk, the rest of the sky is smooth enough to find by chance
+    pub fn sample(&self, (u, v): (Real, Real)) -> Option<SkySample> {
+        if self.sun_radiance == Vector::ZERO {
+            return None;
+        }
+
+        let cos_theta = 1.0 - u * (1.0 - self.cos_sun_size);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let (tangent, bitangent) = self.sun.any_orthonormal_pair();
+        let direction =
+            (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + self.sun * cos_theta;
+
+        Some(SkySample {
+            direction,
+            radiance: self.radiance(direction),
+            pdf: 1.0 / cone_solid_angle(self.cos_sun_size),
+        })
+    }
+
+    pub fn pdf(&self, direction: Vector) -> Real {
+        if self.sun_radiance == Vector::ZERO || !self.in_sun(direction.normalize()) {
+            return 0.0;
+        }
+        1.0 / cone_solid_angle(self.cos_sun_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sky_is_bluer_and_dimmer_than_the_sun() {
+        let sky = Sky::from(SkyParameters::default());
+        let zenith = sky.radiance(Vector::Y);
+        assert!(zenith.z > zenith.x, "{zenith}");
+
+        let sample = sky.sample((0.3, 0.6)).unwrap();
+        assert!(sample.direction.dot(sky.sun) >= sky.cos_sun_size - 1e-9);
+        assert!(sample.radiance.x > sample.radiance.z && sample.radiance.z > 1000.0 * zenith.z);
+        assert_eq!(sky.pdf(sample.direction), sample.pdf);
+        assert_eq!(sky.pdf(Vector::Y), 0.0);
+    }
+}
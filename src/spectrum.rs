@@ -0,0 +1,196 @@
+use std::sync::OnceLock;
+
+use glam::DMat3;
+
+use crate::{Real, Vector};
+
+// Range of wavelengths the paths sample, in nanometers
+const MIN_WAVELENGTH: Real = 380.0;
+const MAX_WAVELENGTH: Real = 780.0;
+const RANGE: Real = MAX_WAVELENGTH - MIN_WAVELENGTH;
+// Wavelengths carried by each path, one per channel of a color
+const COUNT: usize = 3;
+
+// From CIE XYZ to linear sRGB
+const XYZ_TO_RGB: DMat3 = DMat3::from_cols_array(&[
+    3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570,
+]);
+
+// Gaussian with a different width on each side of the peak
+fn lobe(wavelength: Real, peak: Real, width_below: Real, width_above: Real) -> Real {
+    let width = if wavelength < peak {
+        width_below
+    } else {
+        width_above
+    };
+    (-0.5 * ((wavelength - peak) / width).powi(2)).exp()
+}
+
+// CIE 1931 color matching functions, from the fit of Wyman, Sloan and Shirley (2013)
+fn color_matching(wavelength: Real) -> Vector {
+    let l = wavelength;
+    Vector::new(
+        1.056 * lobe(l, 599.8, 37.9, 31.0) + 0.362 * lobe(l, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(l, 501.1, 20.4, 26.2),
+        0.821 * lobe(l, 568.8, 46.9, 40.5) + 0.286 * lobe(l, 530.9, 16.3, 31.1),
+        1.217 * lobe(l, 437.0, 11.8, 36.0) + 0.681 * lobe(l, 459.0, 26.0, 13.8),
+    )
+}
+
+fn smoothstep(edge0: Real, edge1: Real, x: Real) -> Real {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Red, green and blue bands with soft edges that add up to 1 everywhere, so white stays flat
+fn bands(wavelength: Real) -> Vector {
+    let blue = 1.0 - smoothstep(480.0, 500.0, wavelength);
+    let red = smoothstep(580.0, 600.0, wavelength);
+    Vector::new(red, 1.0 - red - blue, blue)
+}
+
+// How the spectra come back to colors and the bands go out from them, worked out once
+struct Conversion {
+    // Scales the channels so a flat spectrum comes out white
+    white: Vector,
+    // Mixes the bands so a color made into a spectrum comes back as the same color
+    upsampling: DMat3,
+    // Integral of the luminance curve, a flat spectrum at 1 has a luminance of 1
+    luminance: Real,
+}
+
+fn conversion() -> &'static Conversion {
+    static CONVERSION: OnceLock<Conversion> = OnceLock::new();
+    CONVERSION.get_or_init(|| {
+        // Midpoint rule at every nanometer
+        let wavelengths = (0..RANGE as usize).map(|i| MIN_WAVELENGTH + i as Real + 0.5);
+        let luminance = wavelengths
+            .clone()
+            .map(|l| color_matching(l).y)
+            .sum::<Real>();
+        let project = |spectrum: &dyn Fn(Real) -> Real| {
+            let xyz = wavelengths
+                .clone()
+                .map(|l| color_matching(l) * spectrum(l))
+                .sum::<Vector>();
+            XYZ_TO_RGB * xyz / luminance
+        };
+
+        let white = project(&|_| 1.0);
+        let bands = DMat3::from_cols(
+            project(&|l| bands(l).x) / white,
+            project(&|l| bands(l).y) / white,
+            project(&|l| bands(l).z) / white,
+        );
+        Conversion {
+            white,
+            upsampling: bands.inverse(),
+            luminance,
+        }
+    })
+}
+
+// What the channels of the colors along a path stand for: red, green and blue, or the light at
+// a few wavelengths spread evenly over the visible range from the first one, the hero (Wilkie et
+// al., 2014)
+#[derive(Debug, Copy, Clone)]
+pub struct Wavelengths {
+    wavelengths: Option<[Real; COUNT]>, // In nanometers
+    terminated: bool,                   // Only the hero is left
+}
+
+impl Wavelengths {
+    pub const RGB: Wavelengths = Wavelengths {
+        wavelengths: None,
+        terminated: false,
+    };
+
+    pub fn sample(u: Real) -> Self {
+        let hero = u * RANGE;
+        let wavelengths = std::array::from_fn(|i| {
+            let offset = (hero + i as Real * RANGE / COUNT as Real) % RANGE;
+            MIN_WAVELENGTH + offset
+        });
+        Self {
+            wavelengths: Some(wavelengths),
+            terminated: false,
+        }
+    }
+
+    pub fn hero(&self) -> Option<Real> {
+        self.wavelengths.map(|wavelengths| wavelengths[0])
+    }
+
+    // The color as the path carries it. Colors turn into spectra as a mix of smooth bands, so
+    // scaling the color scales the spectrum and white stays white
+    pub fn color(&self, rgb: Vector) -> Vector {
+        let wavelengths = match self.wavelengths {
+            Some(wavelengths) => wavelengths,
+            None => return rgb,
+        };
+        let weights = conversion().upsampling * rgb;
+        Vector::from(wavelengths.map(|l| bands(l).dot(weights).max(0.0)))
+    }
+
+    // Once the wavelengths go separate ways, like through glass that splits them, only the hero
+    // is followed. It stands in for the others from then on. Scales the throughput of the path
+    pub fn terminate_secondary(&mut self) -> Vector {
+        if self.wavelengths.is_none() || self.terminated {
+            return Vector::ONE;
+        }
+        self.terminated = true;
+        Vector::new(COUNT as Real, 0.0, 0.0)
+    }
+
+    // Light carried by the path back to red, green and blue
+    pub fn to_rgb(self, light: Vector) -> Vector {
+        let wavelengths = match self.wavelengths {
+            Some(wavelengths) => wavelengths,
+            None => return light,
+        };
+        let conversion = conversion();
+        // Each wavelength was picked with a density of 1 / RANGE
+        let xyz = wavelengths
+            .iter()
+            .zip(light.to_array())
+            .map(|(l, value)| color_matching(*l) * value)
+            .sum::<Vector>()
+            * RANGE
+            / COUNT as Real;
+        XYZ_TO_RGB * xyz / conversion.luminance / conversion.white
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Over many wavelengths, as the renders average them
+    fn round_trip(rgb: Vector) -> Vector {
+        let samples = 1000;
+        (0..samples)
+            .map(|i| {
+                let wavelengths = Wavelengths::sample((i as Real + 0.5) / samples as Real);
+                wavelengths.to_rgb(wavelengths.color(rgb))
+            })
+            .sum::<Vector>()
+            / samples as Real
+    }
+
+    #[test]
+    fn colors_come_back_from_spectra() {
+        let white = round_trip(Vector::ONE);
+        assert!(white.abs_diff_eq(Vector::ONE, 1e-3), "{white}");
+
+        let red = Vector::new(0.65, 0.05, 0.05);
+        let back = round_trip(red);
+        assert!(back.abs_diff_eq(red, 0.01), "{back}");
+
+        // Only the hero is left, and only once
+        let mut wavelengths = Wavelengths::sample(0.3);
+        let light = wavelengths.color(Vector::ONE) * wavelengths.terminate_secondary();
+        assert_eq!(light.y, 0.0);
+        assert!(wavelengths.to_rgb(light).max_element() > 0.0);
+        assert_eq!(wavelengths.terminate_secondary(), Vector::ONE);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::{Real, Vector};
+
+// Densities on a regular grid, read from a Mitsuba style .vol file. In scene files it is written
+// as the path to that file
+#[derive(Clone)]
+pub struct DensityGrid {
+    path: String,
+    resolution: [usize; 3],
+    pub min: Vector,
+    pub max: Vector,
+    densities: Arc<[f32]>,
+    max_density: Real,
+}
+
+impl DensityGrid {
+    // The layout is "VOL" and version 3, then as little endian 32 bit values: the encoding (1 for
+    // floats), the resolution on X, Y and Z, the number of channels, the min and max corners of
+    // the bounds, and finally the densities with X changing the fastest
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Could not read {path}: {e}"))?;
+
+        if bytes.len() < 48 || &bytes[..3] != b"VOL" || bytes[3] != 3 {
+            return Err(format!("{path} is not a version 3 .vol file"));
+        }
+
+        let word = |i: usize| <[u8; 4]>::try_from(&bytes[4 + 4 * i..8 + 4 * i]).unwrap();
+        // Sizes and counts, which can't be 0 or negative
+        let size = |i| {
+            usize::try_from(i32::from_le_bytes(word(i)))
+                .ok()
+                .filter(|&n| n > 0)
+        };
+        let float = |i| f32::from_le_bytes(word(i)) as Real;
+
+        if i32::from_le_bytes(word(0)) != 1 {
+            return Err(format!("{path} does not hold 32 bit floats"));
+        }
+
+        let (resolution, channels) = match (size(1), size(2), size(3), size(4)) {
+            (Some(x), Some(y), Some(z), Some(channels)) => ([x, y, z], channels),
+            _ => return Err(format!("{path} has an empty resolution or no channels")),
+        };
+        let min = Vector::new(float(5), float(6), float(7));
+        let max = Vector::new(float(8), float(9), float(10));
+
+        // Bytes taken by the densities, 4 per value
+        let needed = resolution
+            .iter()
+            .chain([&channels, &4])
+            .try_fold(1usize, |total, n| total.checked_mul(*n))
+            .ok_or_else(|| format!("{path} has a resolution too large to hold"))?;
+        let count = resolution.iter().product::<usize>();
+        let values = &bytes[48..];
+        if values.len() < needed {
+            return Err(format!("{path} is missing densities"));
+        }
+
+        // Only the first channel is a density
+        let densities: Arc<[f32]> = values
+            .chunks_exact(4 * channels)
+            .take(count)
+            .map(|value| f32::from_le_bytes(value[..4].try_into().unwrap()))
+            .collect();
+        let max_density = densities.iter().fold(0.0, |a: f32, b| a.max(*b)) as Real;
+
+        Ok(Self {
+            path: path.to_string(),
+            resolution,
+            min,
+            max,
+            densities,
+            max_density,
+        })
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> Real {
+        let [width, height, _] = self.resolution;
+        self.densities[(z * height + y) * width + x] as Real
+    }
+
+    // Trilinear interpolation between the centers of the voxels around the point
+    pub fn density(&self, point: Vector) -> Real {
+        let resolution = Vector::new(
+            self.resolution[0] as Real,
+            self.resolution[1] as Real,
+            self.resolution[2] as Real,
+        );
+        let local = (point - self.min) / (self.max - self.min) * resolution - Vector::splat(0.5);
+        let last = resolution - Vector::ONE;
+
+        let lower = local.floor().clamp(Vector::ZERO, last);
+        let upper = (lower + Vector::ONE).min(last);
+        let weight = (local - lower).clamp(Vector::ZERO, Vector::ONE);
+
+        let (x0, y0, z0) = (lower.x as usize, lower.y as usize, lower.z as usize);
+        let (x1, y1, z1) = (upper.x as usize, upper.y as usize, upper.z as usize);
+
+        let lerp = |a: Real, b: Real, t: Real| a + (b - a) * t;
+        let along_x = |y, z| lerp(self.voxel(x0, y, z), self.voxel(x1, y, z), weight.x);
+        let along_y = |z| lerp(along_x(y0, z), along_x(y1, z), weight.y);
+        lerp(along_y(z0), along_y(z1), weight.z)
+    }
+
+    // Delta tracking: sample collisions against the densest voxel and keep them with the
+    // probability of the real density there, which leaves the rest as null collisions. Distance
+    // along the ray of the first real one
+    pub fn sample(
+        &self,
+        ray: &Ray,
+        t_start: Real,
+        t_end: Real,
+        scale: Real,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Real> {
+        let majorant = self.max_density * scale;
+        if majorant <= 0.0 {
+            return None;
+        }
+
+        let mut t = t_start;
+
+        loop {
+            t -= (1.0 - sampler.get_1d()).ln() / majorant;
+            if t >= t_end {
+                return None;
+            }
+
+            if sampler.get_1d() * majorant < self.density(ray.point(t)) * scale {
+                return Some(t);
+            }
+        }
+    }
+
+    // Ratio tracking: the same tentative collisions, each one letting through the null part of
+    // the majorant there. Shadow rays get a fraction of the light instead of all or nothing
+    pub fn transmittance(
+        &self,
+        ray: &Ray,
+        t_start: Real,
+        t_end: Real,
+        scale: Real,
+        sampler: &mut dyn Sampler,
+    ) -> Real {
+        let majorant = self.max_density * scale;
+        if majorant <= 0.0 {
+            return 1.0;
+        }
+
+        let mut t = t_start;
+        let mut transmittance = 1.0;
+
+        loop {
+            t -= (1.0 - sampler.get_1d()).ln() / majorant;
+            if t >= t_end {
+                return transmittance;
+            }
+
+            transmittance *= 1.0 - self.density(ray.point(t)) * scale / majorant;
+        }
+    }
+}
+
+impl fmt::Debug for DensityGrid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DensityGrid")
+            .field("path", &self.path)
+            .field("resolution", &self.resolution)
+            .finish()
+    }
+}
+
+impl Serialize for DensityGrid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.path)
+    }
+}
+
+impl<'de> Deserialize<'de> for DensityGrid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        Self::load(&path).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+
+    // Header values, then the bounds and the densities
+    fn write_vol(name: &str, ints: [i32; 5], floats: &[f32]) -> String {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(3);
+        for int in ints {
+            bytes.extend(int.to_le_bytes());
+        }
+        for float in floats {
+            bytes.extend(float.to_le_bytes());
+        }
+
+        let path = std::env::temp_dir().join(format!("raytracer_{name}.vol"));
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    // Two voxels along X, with densities 1 and 3
+    fn two_voxels() -> DensityGrid {
+        let floats = [0.0, 0.0, 0.0, 2.0, 1.0, 1.0, 1.0, 3.0];
+        DensityGrid::load(&write_vol("two_voxels", [1, 2, 1, 1, 1], &floats)).unwrap()
+    }
+
+    #[test]
+    fn load_and_interpolate() {
+        let grid = two_voxels();
+
+        assert_eq!(grid.max_density, 3.0);
+        // Voxel centers are at x = 0.5 and 1.5, past them the density stays flat
+        assert_eq!(grid.density(Vector::new(0.2, 0.5, 0.5)), 1.0);
+        assert_eq!(grid.density(Vector::new(1.0, 0.5, 0.5)), 2.0);
+        assert_eq!(grid.density(Vector::new(1.9, 0.5, 0.5)), 3.0);
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let bounds = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0];
+        for (name, ints) in [
+            ("no_channels", [1, 1, 1, 1, 0]),
+            ("negative_size", [1, -1, 1, 1, 1]),
+            ("empty", [1, 0, 1, 1, 1]),
+            ("huge", [1, i32::MAX, i32::MAX, i32::MAX, 1]),
+        ] {
+            assert!(
+                DensityGrid::load(&write_vol(name, ints, &bounds)).is_err(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn tracking_finds_the_transmittance() {
+        let grid = two_voxels();
+        let mut sampler = SamplerKind::Independent.create(1, 1);
+        let ray = Ray::new(Vector::new(0.0, 0.5, 0.5), Vector::X);
+        // The density adds up to 4 across the grid
+        let (scale, expected) = (0.25, (-1.0 as Real).exp());
+
+        let tries = 20_000;
+        let (mut ratio, mut delta) = (0.0, 0.0);
+        for _ in 0..tries {
+            ratio += grid.transmittance(&ray, 0.0, 2.0, scale, sampler.as_mut());
+            if grid
+                .sample(&ray, 0.0, 2.0, scale, sampler.as_mut())
+                .is_none()
+            {
+                delta += 1.0;
+            }
+        }
+
+        assert!((ratio / tries as Real - expected).abs() < 0.01);
+        assert!((delta / tries as Real - expected).abs() < 0.01);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{
+    bvh::Bvh, config::Config, environment::Environment, hit::Hit, lights::Lights, ray::Ray,
+    sampler::Sampler, scene::Scene, shapes::ShapeRef, Real, Vector,
+};
+
+// Everything the integrators need to follow rays around the scene
+pub struct World {
+    pub scene: Scene,
+    pub bvh: Bvh,
+    pub lights: Lights,
+    pub ambient_color: Vector,
+    pub environment: Environment,
+    // Shapes filled with fog, they are not in the BVH
+    media: Vec<ShapeRef>,
+}
+
+impl World {
+    pub fn new(scene: Scene, config: &Config) -> Self {
+        let bvh = Bvh::new(&scene.shapes);
+        let lights = Lights::new(&scene, config.light_sampling);
+        let media = (0..scene.shapes.len())
+            .filter(|shape| scene.shapes[*shape].kind.is_medium())
+            .collect();
+        let environment = scene
+            .environment
+            .clone()
+            .unwrap_or_else(|| config.environment.clone());
+
+        Self {
+            scene,
+            bvh,
+            lights,
+            ambient_color: config.ambient_color,
+            environment,
+            media,
+        }
+    }
+
+    // Closest surface along the ray. Media don't count, see `sample_hit`
+    pub fn hit(&self, ray: &Ray) -> Option<Hit> {
+        self.bvh.hit(ray, ray.direction.recip(), &self.scene.shapes)
+    }
+
+    pub fn hit_with_cost(&self, ray: &Ray) -> (Option<Hit>, usize) {
+        self.bvh
+            .hit_with_cost(ray, ray.direction.recip(), &self.scene.shapes)
+    }
+
+    // Closest surface along the ray, or the point where the light scatters in a medium before
+    // reaching it. Each medium samples its own distance, the closest one wins
+    pub fn sample_hit(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Option<Hit> {
+        let surface = self.hit(ray);
+        let t_max = surface.map_or(Real::INFINITY, |hit| hit.t);
+        self.media
+            .iter()
+            .filter_map(|medium| {
+                self.scene.shapes[*medium]
+                    .sample_medium(ray, t_max, sampler)
+                    .map(|hit| hit.on_shape(*medium))
+            })
+            .min_by(|a, b| a.t.total_cmp(&b.t))
+            .or(surface)
+    }
+
+    // Light coming from the sky for rays that escape the scene
+    pub fn sky(&self, direction: Vector) -> Vector {
+        self.environment.radiance(direction, self.ambient_color)
+    }
+
+    // Whether no surface is in the way for `distance` along the direction
+    pub fn visible(&self, from: Vector, direction: Vector, distance: Real) -> bool {
+        match self.hit(&Ray::new(from, direction)) {
+            // Leave some room for hitting the surface the light is on
+            Some(hit) => hit.t > distance * (1.0 - 1e-4),
+            None => true,
+        }
+    }
+
+    // Fraction of the light that gets through `distance` along the direction: none past a
+    // surface, and whatever the media in the way let through
+    pub fn transmittance(
+        &self,
+        from: Vector,
+        direction: Vector,
+        distance: Real,
+        sampler: &mut dyn Sampler,
+    ) -> Real {
+        if !self.visible(from, direction, distance) {
+            return 0.0;
+        }
+
+        let ray = Ray::new(from, direction);
+        self.media
+            .iter()
+            .map(|medium| self.scene.shapes[*medium].transmittance(&ray, distance, sampler))
+            .product()
+    }
+}